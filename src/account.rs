@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::auth::{load_tokens, BskyState, ServiceEndpoints};
+use crate::secrets::{secret, try_secret};
+use crate::store::SessionStore;
+
+pub const DEFAULT_ACCOUNT: &str = "default";
+const TOKEN_FILE: &str = "bluesky_tokens.json";
+
+/// Where one account's settings come from. The default account reads `BLUESKY_*`; a named
+/// account such as `work` reads `BLUESKY_WORK_*`, falling back to `BLUESKY_*` for anything
+/// that isn't a credential.
+#[derive(Debug, Clone)]
+pub struct AccountConfig {
+    pub name: String,
+    prefix: String,
+    pub store: SessionStore,
+}
+
+impl AccountConfig {
+    pub fn default_account() -> Self {
+        Self::build(DEFAULT_ACCOUNT, "BLUESKY".to_string(), TOKEN_FILE.to_string())
+    }
+
+    pub fn named(name: &str) -> Self {
+        Self::build(name, env_prefix(name), format!("bluesky_tokens.{}.json", name))
+    }
+
+    fn build(name: &str, prefix: String, file_name: String) -> Self {
+        let mut account = AccountConfig {
+            name: name.to_string(),
+            prefix,
+            store: SessionStore::new(PathBuf::new(), None, None),
+        };
+        account.store = account.session_store(file_name);
+        account
+    }
+
+    /// The session file is `TOKEN_FILE` if the account sets one, otherwise `file_name` inside
+    /// `TOKEN_DIR` (default: the working directory). A `SESSION_KEY` secret turns on encryption.
+    fn session_store(&self, file_name: String) -> SessionStore {
+        let path = match self.var("TOKEN_FILE") {
+            Some(path) => PathBuf::from(path),
+            None => PathBuf::from(self.setting("TOKEN_DIR").unwrap_or_else(|| ".".to_string())).join(file_name),
+        };
+
+        // An unreadable key file must not quietly fall back to a plaintext session file
+        let key = try_secret(&self.var_name("SESSION_KEY")).and_then(|key| match key {
+            Some(key) => Ok(Some(key)),
+            None => try_secret("BLUESKY_SESSION_KEY"),
+        });
+        match key {
+            Ok(key) => SessionStore::new(path, key, None),
+            Err(e) => {
+                println!("Account {}: {}", self.name, e);
+                SessionStore::new(path, None, Some(e))
+            }
+        }
+    }
+
+    /// The environment variable that holds `key` for this account.
+    pub fn var_name(&self, key: &str) -> String {
+        format!("{}_{}", self.prefix, key)
+    }
+
+    /// A setting for this account only, e.g. `USERNAME` reads `BLUESKY_WORK_USERNAME`
+    /// (or the file named by `BLUESKY_WORK_USERNAME_FILE`).
+    /// Used for credentials, which must never leak from one account to another.
+    pub fn var(&self, key: &str) -> Option<String> {
+        secret(&self.var_name(key))
+    }
+
+    /// A setting for this account, falling back to the shared `BLUESKY_<key>` value.
+    pub fn setting(&self, key: &str) -> Option<String> {
+        self.var(key).or_else(|| secret(&format!("BLUESKY_{}", key)))
+    }
+}
+
+/// The environment variable prefix for a named account: `work-2` reads `BLUESKY_WORK_2_*`.
+fn env_prefix(name: &str) -> String {
+    format!("BLUESKY_{}", name.to_ascii_uppercase().replace('-', "_"))
+}
+
+/// Every configured account's state, looked up by the widget's `account` parameter.
+#[derive(Clone)]
+pub struct Accounts {
+    states: HashMap<String, BskyState>,
+}
+
+impl Accounts {
+    /// Builds the default account plus one per name in `BLUESKY_ACCOUNTS` (comma separated).
+    pub fn from_env() -> Self {
+        let mut configs = vec![AccountConfig::default_account()];
+        // `a-b` and `a_b` would read the same variables, so only the first of them is kept
+        let mut prefixes: HashMap<String, String> = HashMap::new();
+        let names = secret("BLUESKY_ACCOUNTS").unwrap_or_default();
+        for name in names.split(',').map(|n| n.trim().to_ascii_lowercase()) {
+            if name.is_empty() || name == DEFAULT_ACCOUNT {
+                continue;
+            }
+            if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                println!("Ignoring account {:?}: names may only contain letters, digits, '-' and '_'", name);
+                continue;
+            }
+            let prefix = env_prefix(&name);
+            if let Some(existing) = prefixes.get(&prefix) {
+                if *existing != name {
+                    println!(
+                        "Ignoring account {:?}: it would share {}_* settings with account {:?}",
+                        name, prefix, existing
+                    );
+                }
+                continue;
+            }
+            prefixes.insert(prefix, name.clone());
+            configs.push(AccountConfig::named(&name));
+        }
+
+        let states = configs
+            .into_iter()
+            .map(|account| {
+                let initial_token = load_tokens(&account);
+                let mut endpoints = ServiceEndpoints::for_account(&account);
+                if let Some(session) = &initial_token {
+                    endpoints.adopt_session(session);
+                }
+                let state = BskyState {
+                    token: Arc::new(Mutex::new(initial_token)),
+                    endpoints: Arc::new(Mutex::new(endpoints)),
+                    oauth_pending: Arc::new(Mutex::new(HashMap::new())),
+                    account,
+                };
+                (state.account.name.clone(), state)
+            })
+            .collect();
+        Accounts { states }
+    }
+
+    /// The named account, or the default one when no name is given.
+    pub fn get(&self, name: Option<&str>) -> Option<&BskyState> {
+        let name = name.map(|n| n.trim().to_ascii_lowercase()).filter(|n| !n.is_empty());
+        self.states.get(name.as_deref().unwrap_or(DEFAULT_ACCOUNT))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &BskyState> {
+        self.states.values()
+    }
+}
@@ -0,0 +1,260 @@
+use html_escape::{encode_double_quoted_attribute, encode_safe};
+use reqwest::Client;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashSet;
+
+use crate::auth::{xrpc_get, BskyState};
+use crate::paging::{collect_page, PageRequest};
+use crate::params::Params;
+use crate::post::BskyProfile;
+
+/// Largest page `searchActors`, `getLikes` and the other account-listing endpoints will return.
+const ACTORS_PAGE_SIZE: usize = 100;
+/// Most actors `getProfiles` accepts in one call.
+const GET_PROFILES_MAX: usize = 25;
+/// How much of a bio to show under each account.
+const DESCRIPTION_SNIPPET_CHARS: usize = 140;
+
+#[derive(Debug, Deserialize)]
+struct BskyActorsResponse {
+    #[serde(default)]
+    actors: Vec<BskyProfile>,
+    #[serde(default)]
+    cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BskyGetProfilesResponse {
+    #[serde(default)]
+    profiles: Vec<BskyProfile>,
+}
+
+/// Searches accounts matching `q` via `app.bsky.actor.searchActors`, or the faster prefix match
+/// `searchActorsTypeahead` (one page, no cursor). Follower counts are filled in afterwards.
+pub async fn search_actors(
+    client: &Client,
+    state: &BskyState,
+    token: Option<&str>,
+    q: &str,
+    typeahead: bool,
+    params: &Params,
+) -> Result<(Vec<BskyProfile>, Option<String>), Box<dyn std::error::Error>> {
+    let (mut actors, cursor) = if typeahead {
+        let query = [("q", q.to_string()), ("limit", params.limit.min(ACTORS_PAGE_SIZE).to_string())];
+        let response = xrpc_get(client, state, "app.bsky.actor.searchActorsTypeahead", token, &query).await?;
+        if !response.status().is_success() {
+            return Err(Box::new(response.error_for_status().unwrap_err()));
+        }
+        let result: BskyActorsResponse = serde_json::from_str(&response.text().await?)?;
+        (result.actors, None)
+    } else {
+        let extract = |result: BskyActorsResponse| (result.actors, result.cursor);
+        fetch_actor_list(
+            client,
+            state,
+            token,
+            "app.bsky.actor.searchActors",
+            vec![("q", q.to_string())],
+            params,
+            extract,
+        )
+        .await?
+    };
+
+    if let Err(e) = fill_profile_counts(client, state, token, &mut actors).await {
+        println!("Could not fetch follower counts: {}", e);
+    }
+    Ok((actors, cursor))
+}
+
+/// Pages through an endpoint that lists accounts (likers, reposters, followers, ...), honouring
+/// `page`, `cursor` and `limit` like the post sources. `extract` pulls the accounts and next cursor
+/// out of the endpoint's response.
+pub async fn fetch_actor_list<R: DeserializeOwned>(
+    client: &Client,
+    state: &BskyState,
+    token: Option<&str>,
+    method: &str,
+    base_query: Vec<(&'static str, String)>,
+    params: &Params,
+    extract: fn(R) -> (Vec<BskyProfile>, Option<String>),
+) -> Result<(Vec<BskyProfile>, Option<String>), Box<dyn std::error::Error>> {
+    let request = PageRequest {
+        method,
+        token,
+        query: &base_query,
+        page_size: ACTORS_PAGE_SIZE,
+    };
+    collect_page(client, state, &request, params, extract, |_| true).await
+}
+
+/// Search results are basic profile views without counts; `getProfiles` has them.
+pub async fn fill_profile_counts(
+    client: &Client,
+    state: &BskyState,
+    token: Option<&str>,
+    actors: &mut [BskyProfile],
+) -> Result<(), Box<dyn std::error::Error>> {
+    for chunk in actors.chunks_mut(GET_PROFILES_MAX) {
+        let query: Vec<(&str, String)> = chunk.iter().map(|a| ("actors", a.did.clone())).collect();
+        let response = xrpc_get(client, state, "app.bsky.actor.getProfiles", token, &query).await?;
+        if !response.status().is_success() {
+            return Err(Box::new(response.error_for_status().unwrap_err()));
+        }
+        let result: BskyGetProfilesResponse = serde_json::from_str(&response.text().await?)?;
+        for actor in chunk.iter_mut() {
+            if let Some(detailed) = result.profiles.iter().find(|p| p.did == actor.did) {
+                actor.followers_count = detailed.followers_count;
+                actor.follows_count = detailed.follows_count;
+                actor.posts_count = detailed.posts_count;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Renders accounts as a collapsible list: avatar, display name, handle, a bio snippet and counts.
+/// Accounts whose DID is in `new` are badged, with a count above the list.
+pub fn build_actors_html(actors: &[BskyProfile], new: &HashSet<String>, body: &mut String, params: &Params) {
+    if actors.is_empty() {
+        body.push_str("<p>No accounts found.</p>");
+        return;
+    }
+    if !new.is_empty() {
+        body.push_str(&format!(r#"<p class="post-stats">{} new since last update</p>"#, new.len()));
+    }
+    body.push_str(&format!(
+        r#"<ul class="list collapsible-container" data-collapse-after="{}">"#,
+        params.collapse_after
+    ));
+    for actor in actors {
+        body.push_str(r#"<li class="post-container"><div class="profile-header">"#);
+        build_actor_html(actor, body, params);
+        if new.contains(&actor.did) {
+            body.push_str(r#"<span class="new-badge">new</span>"#);
+        }
+        body.push_str("</div></li>");
+    }
+    body.push_str("</ul>");
+}
+
+/// Renders accounts as a compact row of linked avatars, e.g. everyone who liked a post.
+/// `more` notes that the list goes on past what was fetched.
+pub fn build_avatar_strip_html(actors: &[BskyProfile], label: &str, more: bool, body: &mut String) {
+    if actors.is_empty() {
+        body.push_str(&format!("<p>No one has {} this post yet.</p>", encode_safe(label)));
+        return;
+    }
+    body.push_str(&format!(
+        r#"<p class="post-stats">{} by {}{} {}</p><div class="avatar-strip">"#,
+        encode_safe(&capitalize(label)),
+        actors.len(),
+        if more { "+" } else { "" },
+        if actors.len() == 1 && !more { "account" } else { "accounts" }
+    ));
+    for actor in actors {
+        let name = actor
+            .display_name
+            .as_deref()
+            .filter(|n| !n.trim().is_empty())
+            .unwrap_or(&actor.handle);
+        body.push_str(&format!(
+            r#"<a href="https://bsky.app/profile/{}" target="_blank" title="{}">"#,
+            encode_double_quoted_attribute(&actor.handle),
+            encode_double_quoted_attribute(name)
+        ));
+        match &actor.avatar {
+            Some(avatar) => body.push_str(&format!(
+                r#"<img class="strip-avatar" src="{}" alt="{}" loading="lazy"/>"#,
+                encode_double_quoted_attribute(avatar),
+                encode_double_quoted_attribute(name)
+            )),
+            None => body.push_str(&format!(
+                r#"<span class="strip-avatar">{}</span>"#,
+                encode_safe(&name.chars().next().unwrap_or('?').to_uppercase().to_string())
+            )),
+        }
+        body.push_str("</a>");
+    }
+    body.push_str("</div>");
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// One account's avatar and details, without the surrounding list item.
+pub fn build_actor_html(actor: &BskyProfile, body: &mut String, params: &Params) {
+    let profile_link = format!("https://bsky.app/profile/{}", encode_double_quoted_attribute(&actor.handle));
+    if let Some(avatar) = &actor.avatar {
+        body.push_str(&format!(
+            r#"<img class="actor-avatar" src="{}" alt="" loading="lazy"/>"#,
+            encode_double_quoted_attribute(avatar)
+        ));
+    }
+    let name = actor
+        .display_name
+        .as_deref()
+        .filter(|n| !n.trim().is_empty())
+        .unwrap_or(&actor.handle);
+    body.push_str(&format!(
+        r#"<div><p class="post-text"><a href="{}" target="_blank">{}</a></p>"#,
+        profile_link,
+        encode_safe(name)
+    ));
+    if !params.hide_author {
+        body.push_str(&format!(r#"<p class="post-author">@{}</p>"#, encode_safe(&actor.handle)));
+    }
+    if let Some(description) = actor.description.as_deref().map(str::trim).filter(|d| !d.is_empty()) {
+        body.push_str(&format!(r#"<p class="post-author">{}</p>"#, encode_safe(&snippet(description))));
+    }
+    if !params.hide_stats {
+        if let Some(followers) = actor.followers_count {
+            body.push_str(&format!(
+                r#"<p class="post-stats">Followers: {} &nbsp;&middot;&nbsp; Following: {}</p>"#,
+                followers,
+                actor.follows_count.unwrap_or(0)
+            ));
+        }
+    }
+    body.push_str("</div>");
+}
+
+/// The first line of a bio, cut to `DESCRIPTION_SNIPPET_CHARS` characters.
+fn snippet(description: &str) -> String {
+    let line = description.lines().next().unwrap_or_default();
+    if line.chars().count() > DESCRIPTION_SNIPPET_CHARS {
+        let cut: String = line.chars().take(DESCRIPTION_SNIPPET_CHARS).collect();
+        format!("{}…", cut.trim_end())
+    } else {
+        line.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn avatar_strip_escapes_handles_in_links() {
+        let actor: BskyProfile = serde_json::from_value(json!({
+            "did": "did:plc:a",
+            "handle": r#"x" onmouseover="alert(1)"#,
+        }))
+        .unwrap();
+        let mut body = String::new();
+        build_avatar_strip_html(&[actor], "liked", false, &mut body);
+        assert!(!body.contains(r#"" onmouseover"#), "{}", body);
+        assert!(
+            body.contains("https://bsky.app/profile/x&quot; onmouseover=&quot;alert(1)"),
+            "{}",
+            body
+        );
+    }
+}
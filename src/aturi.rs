@@ -0,0 +1,197 @@
+use reqwest::Client;
+use serde::Deserialize;
+
+use crate::auth::{xrpc_get, BskyState};
+
+/// A record named by an `at://` URI or a bsky.app link, before its handle (if any) is resolved.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordRef {
+    /// A handle or DID.
+    pub authority: String,
+    pub collection: &'static str,
+    pub rkey: String,
+}
+
+/// The bsky.app path segment for records of `collection`, e.g. `/profile/<actor>/feed/<rkey>`.
+fn web_segment(collection: &str) -> Option<&'static str> {
+    match collection {
+        "app.bsky.feed.generator" => Some("feed"),
+        "app.bsky.graph.list" => Some("lists"),
+        "app.bsky.feed.post" => Some("post"),
+        _ => None,
+    }
+}
+
+impl RecordRef {
+    /// Accepts `at://<handle or did>/<collection>/<rkey>` or a web link such as
+    /// `https://bsky.app/profile/<handle or did>/feed/<rkey>`, for a record of `collection`.
+    pub fn parse(input: &str, collection: &'static str) -> Result<Self, String> {
+        let input = input.trim();
+        let parts: Vec<&str> = if let Some(path) = input.strip_prefix("at://") {
+            let parts: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+            match parts.as_slice() {
+                [authority, c, rkey] if *c == collection => vec![authority, rkey],
+                _ => return Err(format!("\"{}\" is not an at:// URI for a record of type {}", input, collection)),
+            }
+        } else if let Some(rest) = input.strip_prefix("https://").or_else(|| input.strip_prefix("http://")) {
+            // Drop the host and any query or fragment
+            let path = rest.split(['?', '#']).next().unwrap_or_default();
+            let parts: Vec<&str> = path.trim_end_matches('/').split('/').skip(1).collect();
+            let segment = web_segment(collection);
+            match parts.as_slice() {
+                ["profile", authority, s, rkey] if Some(*s) == segment => vec![authority, rkey],
+                // Starter packs live outside /profile: /starter-pack/<handle or did>/<rkey>
+                ["starter-pack", authority, rkey] if collection == "app.bsky.graph.starterpack" => vec![authority, rkey],
+                _ => return Err(format!("\"{}\" is not a link to a record of type {}", input, collection)),
+            }
+        } else {
+            return Err(format!("\"{}\" should be an at:// URI or a bsky.app link", input));
+        };
+        match parts.as_slice() {
+            [authority, rkey] if !authority.is_empty() && !rkey.is_empty() => Ok(RecordRef {
+                authority: authority.to_string(),
+                collection,
+                rkey: rkey.to_string(),
+            }),
+            _ => Err(format!("\"{}\" is missing its account or record key", input)),
+        }
+    }
+
+    /// The `at://` URI with the authority resolved to a DID, which is what the AppView expects.
+    pub async fn resolve(&self, client: &Client, state: &BskyState, token: Option<&str>) -> Result<String, Box<dyn std::error::Error>> {
+        let did = resolve_did(client, state, token, &self.authority).await?;
+        Ok(format!("at://{}/{}/{}", did, self.collection, self.rkey))
+    }
+}
+
+/// The bsky.app link for an `at://` post URI; bsky.app accepts DIDs in place of handles.
+pub fn post_web_link(uri: &str) -> Option<String> {
+    let rest = uri.strip_prefix("at://")?;
+    let mut parts = rest.split('/');
+    let (did, _, rkey) = (parts.next()?, parts.next()?, parts.next()?);
+    Some(format!("https://bsky.app/profile/{}/post/{}", did, rkey))
+}
+
+/// Resolves a handle to its DID through the AppView; DIDs are returned as they are.
+pub async fn resolve_did(
+    client: &Client,
+    state: &BskyState,
+    token: Option<&str>,
+    actor: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    #[derive(Deserialize)]
+    struct ResolveHandleResponse {
+        did: String,
+    }
+
+    let actor = actor.trim_start_matches('@');
+    if actor.starts_with("did:") {
+        return Ok(actor.to_string());
+    }
+    let response = xrpc_get(client, state, "com.atproto.identity.resolveHandle", token, &[("handle", actor)]).await?;
+    if !response.status().is_success() {
+        return Err(format!("could not resolve handle {}: {}", actor, response.status()).into());
+    }
+    let resolved: ResolveHandleResponse = response.json().await?;
+    Ok(resolved.did)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FEED: &str = "app.bsky.feed.generator";
+    const LIST: &str = "app.bsky.graph.list";
+    const POST: &str = "app.bsky.feed.post";
+    const STARTER_PACK: &str = "app.bsky.graph.starterpack";
+
+    fn record(authority: &str, collection: &'static str, rkey: &str) -> RecordRef {
+        RecordRef {
+            authority: authority.to_string(),
+            collection,
+            rkey: rkey.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_at_uris_of_the_expected_collection() {
+        assert_eq!(
+            RecordRef::parse(" at://did:plc:abc/app.bsky.feed.generator/whats-hot/ ", FEED),
+            Ok(record("did:plc:abc", FEED, "whats-hot"))
+        );
+        assert_eq!(
+            RecordRef::parse("at://alice.bsky.social/app.bsky.graph.list/3kabc", LIST),
+            Ok(record("alice.bsky.social", LIST, "3kabc"))
+        );
+    }
+
+    #[test]
+    fn parses_bsky_app_links_for_feeds_lists_and_posts() {
+        assert_eq!(
+            RecordRef::parse("https://bsky.app/profile/bsky.app/feed/whats-hot", FEED),
+            Ok(record("bsky.app", FEED, "whats-hot"))
+        );
+        assert_eq!(
+            RecordRef::parse("https://bsky.app/profile/alice.bsky.social/lists/3kabc/", LIST),
+            Ok(record("alice.bsky.social", LIST, "3kabc"))
+        );
+        assert_eq!(
+            RecordRef::parse("http://bsky.app/profile/did:plc:abc/post/3lxyz", POST),
+            Ok(record("did:plc:abc", POST, "3lxyz"))
+        );
+    }
+
+    #[test]
+    fn parses_starter_pack_links_outside_profile() {
+        assert_eq!(
+            RecordRef::parse("https://bsky.app/starter-pack/alice.bsky.social/3kpack", STARTER_PACK),
+            Ok(record("alice.bsky.social", STARTER_PACK, "3kpack"))
+        );
+        assert_eq!(
+            RecordRef::parse("at://did:plc:abc/app.bsky.graph.starterpack/3kpack", STARTER_PACK),
+            Ok(record("did:plc:abc", STARTER_PACK, "3kpack"))
+        );
+        assert!(RecordRef::parse("https://bsky.app/starter-pack/alice.bsky.social/3kpack", LIST).is_err());
+    }
+
+    #[test]
+    fn strips_query_and_fragment_from_links() {
+        assert_eq!(
+            RecordRef::parse("https://bsky.app/profile/bsky.app/post/3lxyz?ref=share#replies", POST),
+            Ok(record("bsky.app", POST, "3lxyz"))
+        );
+        assert_eq!(
+            RecordRef::parse("https://bsky.app/profile/bsky.app/feed/whats-hot#top", FEED),
+            Ok(record("bsky.app", FEED, "whats-hot"))
+        );
+    }
+
+    #[test]
+    fn rejects_other_collections_and_missing_parts() {
+        for (input, collection) in [
+            ("at://did:plc:abc/app.bsky.feed.post/3lxyz", FEED),
+            ("https://bsky.app/profile/bsky.app/post/3lxyz", FEED),
+            ("https://bsky.app/profile/bsky.app/lists/3kabc", POST),
+            ("at://did:plc:abc/app.bsky.feed.generator", FEED),
+            ("at://did:plc:abc/app.bsky.feed.generator/a/b", FEED),
+            ("at:///app.bsky.feed.generator/whats-hot", FEED),
+            ("https://bsky.app/profile//feed/whats-hot", FEED),
+            ("https://bsky.app/profile/bsky.app/feed/", FEED),
+            ("https://bsky.app/starter-pack/alice.bsky.social", STARTER_PACK),
+            ("bsky.app/profile/bsky.app/feed/whats-hot", FEED),
+            ("", FEED),
+        ] {
+            assert!(RecordRef::parse(input, collection).is_err(), "{:?} should be rejected", input);
+        }
+    }
+
+    #[test]
+    fn links_posts_on_bsky_app() {
+        assert_eq!(
+            post_web_link("at://did:plc:abc/app.bsky.feed.post/3lxyz").as_deref(),
+            Some("https://bsky.app/profile/did:plc:abc/post/3lxyz")
+        );
+        assert_eq!(post_web_link("at://did:plc:abc/app.bsky.feed.post"), None);
+        assert_eq!(post_web_link("https://bsky.app/profile/bsky.app/post/3lxyz"), None);
+    }
+}
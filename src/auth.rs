@@ -1,14 +1,51 @@
-use actix_web::web;
-use reqwest::Client;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use reqwest::{Client, Url};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::env;
-use std::fs;
-use std::path::Path;
-use std::sync::Arc;
+
+use crate::account::AccountConfig;
+use crate::did::resolve_pds_endpoint;
+use crate::oauth::{dpop_request, refresh_oauth_session, OAuthBinding, OAuthError, PendingAuthorization};
+use crate::secrets;
+use std::collections::HashMap;
+use std::io::{IsTerminal, Write};
+use std::sync::{Arc, OnceLock};
 use tokio::sync::Mutex;
+use tokio::time::sleep;
+
+const DEFAULT_BASE_URL: &str = "https://bsky.social";
+const DEFAULT_PUBLIC_APPVIEW_URL: &str = "https://public.api.bsky.app";
+const DEFAULT_REFRESH_MARGIN_SECS: i64 = 60;
+const REFRESHER_MIN_BACKOFF_SECS: u64 = 5;
+const REFRESHER_MAX_BACKOFF_SECS: u64 = 300;
+const REFRESHER_MAX_SLEEP_SECS: i64 = 300;
+
+/// How the server authenticates to Bluesky, from `BLUESKY_AUTH_MODE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMode {
+    /// `createSession` with `BLUESKY_USERNAME`/`BLUESKY_PASSWORD`.
+    AppPassword,
+    /// Browser sign-in through `/oauth/login`.
+    OAuth,
+    /// No account at all; reads go to the public AppView without a token.
+    Public,
+}
 
-const TOKEN_FILE: &str = "bluesky_tokens.json";
+impl AuthMode {
+    /// Explicit `app-password`, `oauth` or `public`; otherwise app passwords when the account
+    /// has credentials configured and public mode when it doesn't.
+    pub fn for_account(account: &AccountConfig) -> Self {
+        match account.setting("AUTH_MODE").unwrap_or_default().to_ascii_lowercase().as_str() {
+            "oauth" => AuthMode::OAuth,
+            "public" => AuthMode::Public,
+            "app-password" => AuthMode::AppPassword,
+            _ if account.var("USERNAME").is_some() || account.var("PASSWORD").is_some() => AuthMode::AppPassword,
+            _ => AuthMode::Public,
+        }
+    }
+}
 
 /// Represents the session token retrieved from Bluesky login.
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -18,58 +55,388 @@ pub struct BskySession {
     #[serde(rename = "refreshJwt")]
     pub refresh_jwt: String,
     pub did: String,
+    /// PDS endpoint discovered from the account's DID document, cached so restarts skip the lookup.
+    #[serde(rename = "pdsEndpoint", default, skip_serializing_if = "Option::is_none")]
+    pub pds_endpoint: Option<String>,
+    /// Present when the session came from the OAuth flow; its tokens are DPoP-bound.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oauth: Option<OAuthBinding>,
+}
+
+impl BskySession {
+    /// When the access token stops being usable.
+    pub fn access_expiry(&self) -> Option<DateTime<Utc>> {
+        match &self.oauth {
+            Some(binding) => binding.expiry(),
+            None => jwt_expiry(&self.access_jwt),
+        }
+    }
+
+    /// When the access token was issued, if we know.
+    fn access_issued(&self) -> Option<DateTime<Utc>> {
+        match &self.oauth {
+            Some(binding) => binding.issued(),
+            None => jwt_claims(&self.access_jwt)?.issued(),
+        }
+    }
+
+    /// `margin`, capped at half the access token's lifetime, so a margin as long as the lifetime
+    /// doesn't make every new token look due for a refresh straight away.
+    fn refresh_margin(&self, margin: Duration) -> Duration {
+        match (self.access_issued(), self.access_expiry()) {
+            (Some(issued), Some(expiry)) => margin.min((expiry - issued) / 2),
+            _ => margin,
+        }
+    }
+
+    /// Whether the access token is still good for longer than `margin`.
+    fn access_valid(&self, margin: Duration) -> bool {
+        is_token_valid(self.access_expiry(), self.refresh_margin(margin))
+    }
+
+    /// Whether a refresh is worth attempting. OAuth refresh tokens are opaque, so only
+    /// the authorization server can tell us they've expired.
+    fn can_refresh(&self) -> bool {
+        self.oauth.is_some() || is_token_valid(jwt_expiry(&self.refresh_jwt), Duration::zero())
+    }
+}
+
+/// Where XRPC calls are sent: authenticated calls go to the PDS, which proxies `app.bsky.*` reads
+/// to an AppView; unauthenticated reads go straight to the public AppView.
+#[derive(Debug, Clone)]
+pub struct ServiceEndpoints {
+    /// The configured `BASE_URL`, where sessions are created and refreshed. For bsky.social
+    /// accounts that's the entryway, not the PDS the account actually lives on.
+    pub session_service: String,
+    /// Where authenticated XRPC calls go: the PDS from the account's DID document once known,
+    /// otherwise `session_service`.
+    pub pds: String,
+    /// `atproto-proxy` header value (`<did>#bsky_appview`) naming the AppView the PDS should
+    /// proxy reads to; when unset the PDS uses its default.
+    pub appview_proxy: Option<String>,
+    /// AppView used for unauthenticated reads.
+    pub public_appview: String,
 }
 
-/// A small struct to hold our Bluesky token in an Arc<Mutex> so we can share it.
+impl ServiceEndpoints {
+    /// Resolves endpoints from the account's `BASE_URL` setting and its optional AppView, named by
+    /// `APPVIEW_DID` or by `APPVIEW_URL` (whose host is taken as a `did:web`).
+    pub fn for_account(account: &AccountConfig) -> Self {
+        let pds = account.setting("BASE_URL").unwrap_or_else(|| DEFAULT_BASE_URL.to_string());
+        let appview_did = account.setting("APPVIEW_DID").or_else(|| {
+            let url = account.setting("APPVIEW_URL")?;
+            match Url::parse(&url).ok().and_then(|u| u.host_str().map(str::to_string)) {
+                Some(host) => Some(format!("did:web:{}", host)),
+                None => {
+                    println!("Ignoring {}: {:?} is not a URL", account.var_name("APPVIEW_URL"), url);
+                    None
+                }
+            }
+        });
+        let public_appview = account
+            .setting("PUBLIC_APPVIEW_URL")
+            .unwrap_or_else(|| DEFAULT_PUBLIC_APPVIEW_URL.to_string());
+        let pds = pds.trim_end_matches('/').to_string();
+        ServiceEndpoints {
+            session_service: pds.clone(),
+            pds,
+            appview_proxy: appview_did.map(|did| format!("{}#bsky_appview", did.trim())),
+            public_appview: public_appview.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Points XRPC traffic at the PDS discovered for `session`, if any. Session management stays
+    /// with `session_service`.
+    pub fn adopt_session(&mut self, session: &BskySession) {
+        if let Some(pds) = &session.pds_endpoint {
+            if *pds != self.pds {
+                println!("Using PDS {} from DID document.", pds);
+                self.pds = pds.clone();
+            }
+        }
+    }
+
+    /// Full URL of a session management method (`createSession`, `refreshSession`).
+    pub fn session_xrpc(&self, method: &str) -> String {
+        format!("{}/xrpc/{}", self.session_service, method)
+    }
+
+    /// Full URL of an XRPC method on the PDS.
+    pub fn pds_xrpc(&self, method: &str) -> String {
+        format!("{}/xrpc/{}", self.pds, method)
+    }
+
+    /// Full URL of an XRPC method on the public, unauthenticated AppView.
+    pub fn public_xrpc(&self, method: &str) -> String {
+        format!("{}/xrpc/{}", self.public_appview, method)
+    }
+}
+
+/// A small struct to hold one account's Bluesky token in an Arc<Mutex> so we can share it.
 #[derive(Clone)]
 pub struct BskyState {
+    pub account: AccountConfig,
     pub token: Arc<Mutex<Option<BskySession>>>,
+    pub endpoints: Arc<Mutex<ServiceEndpoints>>,
+    /// OAuth authorization requests waiting for their callback, keyed by `state`.
+    pub oauth_pending: Arc<Mutex<HashMap<String, PendingAuthorization>>>,
+}
+
+/// Persists the account's session. Failures are logged rather than fatal, so a read-only
+/// filesystem only costs us a login after restart.
+pub fn save_tokens(account: &AccountConfig, session: &BskySession) {
+    if let Err(e) = account.store.save(session) {
+        println!("Could not save session to {}: {}", account.store.path().display(), e);
+    }
+}
+
+pub fn load_tokens(account: &AccountConfig) -> Option<BskySession> {
+    match account.store.load() {
+        Ok(session) => session,
+        Err(e) => {
+            println!("Could not load session from {}: {}", account.store.path().display(), e);
+            None
+        }
+    }
+}
+
+/// Why `bluesky_login` couldn't create a session.
+#[derive(Debug)]
+pub enum LoginError {
+    /// `BLUESKY_USERNAME` or `BLUESKY_PASSWORD` isn't set.
+    MissingCredentials(String),
+    /// The account has email 2FA enabled and no sign-in code was supplied.
+    AuthFactorTokenRequired,
+    /// OAuth mode is on, so a person has to sign in through the browser.
+    OAuthLoginRequired,
+    /// The server rejected the login, with its XRPC error name and message.
+    Rejected { error: String, message: String },
+    /// Network, HTTP or decoding failure.
+    Request(reqwest::Error),
+}
+
+impl std::fmt::Display for LoginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoginError::MissingCredentials(var) => write!(f, "{} is not set", var),
+            LoginError::AuthFactorTokenRequired => write!(
+                f,
+                "this account requires an email sign-in code; set BLUESKY_AUTH_FACTOR_TOKEN to the code Bluesky emailed you"
+            ),
+            LoginError::OAuthLoginRequired => write!(f, "not signed in; visit /oauth/login on this server to sign in with Bluesky"),
+            LoginError::Rejected { error, message } => write!(f, "{}: {}", error, message),
+            LoginError::Request(err) => write!(f, "{}", err),
+        }
+    }
 }
 
-pub fn save_tokens(session: &BskySession) {
-    let json = serde_json::to_string(session).expect("Failed to serialize token data");
-    fs::write(TOKEN_FILE, json).expect("Failed to write token file");
+impl LoginError {
+    /// Whether retrying is pointless until someone signs in, supplies a code or fixes the settings.
+    fn needs_person(&self) -> bool {
+        matches!(
+            self,
+            LoginError::MissingCredentials(_) | LoginError::AuthFactorTokenRequired | LoginError::OAuthLoginRequired
+        )
+    }
 }
 
-pub fn load_tokens() -> Option<BskySession> {
-    if Path::new(TOKEN_FILE).exists() {
-        let json = fs::read_to_string(TOKEN_FILE).expect("Failed to read token file");
-        serde_json::from_str(&json).ok()
-    } else {
-        None
+impl std::error::Error for LoginError {}
+
+impl From<reqwest::Error> for LoginError {
+    fn from(err: reqwest::Error) -> Self {
+        LoginError::Request(err)
     }
 }
 
-pub async fn bluesky_login(client: &Client) -> Result<BskySession, Box<dyn std::error::Error>> {
+/// Accounts whose last login was refused for want of an emailed sign-in code, with the secrets
+/// generation it happened in. Every attempt makes Bluesky email another code, so they aren't
+/// retried until a code is typed in or secrets are reloaded.
+fn awaiting_auth_factor() -> &'static std::sync::Mutex<HashMap<String, u64>> {
+    static AWAITING: OnceLock<std::sync::Mutex<HashMap<String, u64>>> = OnceLock::new();
+    AWAITING.get_or_init(|| std::sync::Mutex::new(HashMap::new()))
+}
+
+pub async fn bluesky_login(client: &Client, account: &AccountConfig, endpoints: &ServiceEndpoints) -> Result<BskySession, LoginError> {
+    login(client, account, endpoints, None).await
+}
+
+/// Logs in with the account's credentials, and `typed_code` or the configured `AUTH_FACTOR_TOKEN`
+/// as the email sign-in code.
+async fn login(
+    client: &Client,
+    account: &AccountConfig,
+    endpoints: &ServiceEndpoints,
+    typed_code: Option<String>,
+) -> Result<BskySession, LoginError> {
+    if AuthMode::for_account(account) == AuthMode::OAuth {
+        return Err(LoginError::OAuthLoginRequired);
+    }
+
     // Load creds from environment
-    let username = env::var("BLUESKY_USERNAME")?;
-    let password = env::var("BLUESKY_PASSWORD")?;
-    let base_url = env::var("BLUESKY_BASE_URL").unwrap_or_else(|_| "https://bsky.social".to_string());
+    let username = account
+        .var("USERNAME")
+        .ok_or_else(|| LoginError::MissingCredentials(account.var_name("USERNAME")))?;
+    let password = account
+        .var("PASSWORD")
+        .ok_or_else(|| LoginError::MissingCredentials(account.var_name("PASSWORD")))?;
 
-    let url = format!("{}/xrpc/com.atproto.server.createSession", base_url);
+    let awaiting = awaiting_auth_factor().lock().unwrap_or_else(|e| e.into_inner()).get(&account.name) == Some(&secrets::generation());
+    if awaiting && typed_code.is_none() {
+        return Err(LoginError::AuthFactorTokenRequired);
+    }
+    let auth_factor_token = typed_code.or_else(|| account.var("AUTH_FACTOR_TOKEN"));
 
-    // Send the login request
-    let resp = client
-        .post(url)
-        .json(&json!({ "identifier": username, "password": password }))
-        .send()
-        .await?
-        .error_for_status()?;
+    let result = create_session(client, endpoints, &username, &password, auth_factor_token.as_deref()).await;
+    {
+        let mut awaiting = awaiting_auth_factor().lock().unwrap_or_else(|e| e.into_inner());
+        match &result {
+            Err(LoginError::AuthFactorTokenRequired) => {
+                awaiting.insert(account.name.clone(), secrets::generation());
+            }
+            Ok(_) => {
+                awaiting.remove(&account.name);
+            }
+            Err(_) => {}
+        }
+    }
+    let mut session = result?;
 
-    // Deserialize to get the session token
-    let session: BskySession = resp.json().await?;
-    save_tokens(&session);
+    session.pds_endpoint = discover_pds(client, account, &session.did).await;
+    save_tokens(account, &session);
     println!("Logged in and obtained new token.");
     Ok(session)
 }
 
-pub async fn refresh_access_token(refresh_jwt: &str) -> Option<BskySession> {
-    #[derive(Serialize)]
-    struct RefreshRequest {
-        #[serde(rename = "refreshJwt")]
-        refresh_jwt: String,
+/// Logs in an app-password account that has no usable session before the server starts taking
+/// requests, so an emailed sign-in code can be typed at the terminal without a widget request
+/// waiting on it.
+pub async fn sign_in_at_startup(state: &BskyState) {
+    if AuthMode::for_account(&state.account) != AuthMode::AppPassword {
+        return;
+    }
+    if state.token.lock().await.as_ref().is_some_and(|session| session.can_refresh()) {
+        return;
+    }
+    let client = Client::new();
+    let endpoints = state.endpoints.lock().await.clone();
+    let mut result = bluesky_login(&client, &state.account, &endpoints).await;
+    if matches!(result, Err(LoginError::AuthFactorTokenRequired)) {
+        if let Some(code) = prompt_auth_factor_token(&state.account).await {
+            result = login(&client, &state.account, &endpoints, Some(code)).await;
+        }
+    }
+    match result {
+        Ok(session) => {
+            state.endpoints.lock().await.adopt_session(&session);
+            *state.token.lock().await = Some(session);
+        }
+        Err(e) => println!("Could not sign in account {}: {}", state.account.name, e),
+    }
+}
+
+async fn create_session(
+    client: &Client,
+    endpoints: &ServiceEndpoints,
+    username: &str,
+    password: &str,
+    auth_factor_token: Option<&str>,
+) -> Result<BskySession, LoginError> {
+    #[derive(Deserialize)]
+    struct XrpcError {
+        #[serde(default)]
+        error: String,
+        #[serde(default)]
+        message: String,
+    }
+
+    let url = endpoints.session_xrpc("com.atproto.server.createSession");
+    let mut payload = json!({ "identifier": username, "password": password });
+    if let Some(code) = auth_factor_token {
+        payload["authFactorToken"] = json!(code.trim());
+    }
+
+    // Send the login request
+    let resp = client.post(url).json(&payload).send().await?;
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let err: XrpcError = resp.json().await.unwrap_or(XrpcError {
+            error: status.to_string(),
+            message: String::new(),
+        });
+        return Err(if err.error == "AuthFactorTokenRequired" {
+            LoginError::AuthFactorTokenRequired
+        } else {
+            LoginError::Rejected {
+                error: err.error,
+                message: err.message,
+            }
+        });
+    }
+
+    // Deserialize to get the session token
+    Ok(resp.json().await?)
+}
+
+/// Asks for the emailed sign-in code on the terminal, when stdin is interactive.
+async fn prompt_auth_factor_token(account: &AccountConfig) -> Option<String> {
+    if !std::io::stdin().is_terminal() {
+        return None;
+    }
+    let name = account.name.clone();
+    tokio::task::spawn_blocking(move || {
+        print!("Bluesky emailed a sign-in code for account {}, enter it to continue: ", name);
+        std::io::stdout().flush().ok()?;
+        let mut code = String::new();
+        std::io::stdin().read_line(&mut code).ok()?;
+        Some(code.trim().to_string()).filter(|c| !c.is_empty())
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// Looks up the account's PDS from its DID document unless `BLUESKY_PDS_DISCOVERY=false`.
+/// Failures are logged and leave the configured `BLUESKY_BASE_URL` in use.
+async fn discover_pds(client: &Client, account: &AccountConfig, did: &str) -> Option<String> {
+    let enabled = account
+        .setting("PDS_DISCOVERY")
+        .and_then(|s| s.parse::<bool>().ok())
+        .unwrap_or(true);
+    if !enabled {
+        return None;
+    }
+    match resolve_pds_endpoint(client, account, did).await {
+        Ok(pds) => Some(pds),
+        Err(e) => {
+            println!("PDS discovery failed for {}: {}", did, e);
+            None
+        }
     }
+}
 
+/// Why a refresh attempt didn't produce a new session.
+#[derive(Debug)]
+pub enum RefreshError {
+    /// The server refused the refresh JWT (expired or revoked); only a fresh login will help.
+    Rejected(String),
+    /// Network or server trouble; worth retrying with the same refresh JWT later.
+    Failed(String),
+}
+
+impl std::fmt::Display for RefreshError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RefreshError::Rejected(msg) => write!(f, "refresh token rejected: {}", msg),
+            RefreshError::Failed(msg) => write!(f, "refresh failed: {}", msg),
+        }
+    }
+}
+
+pub async fn refresh_access_token(
+    account: &AccountConfig,
+    endpoints: &ServiceEndpoints,
+    current: &BskySession,
+) -> Result<BskySession, RefreshError> {
     #[derive(Deserialize)]
     struct RefreshResponse {
         #[serde(rename = "accessJwt")]
@@ -80,57 +447,80 @@ pub async fn refresh_access_token(refresh_jwt: &str) -> Option<BskySession> {
     }
 
     let client = Client::new();
-    let refresh_data = RefreshRequest {
-        refresh_jwt: refresh_jwt.to_string(),
-    };
 
-    match client
-        .post("https://bsky.social/xrpc/com.atproto.server.refreshSession")
-        .json(&refresh_data)
+    // refreshSession authenticates with the refresh JWT itself as the bearer token
+    let response = client
+        .post(endpoints.session_xrpc("com.atproto.server.refreshSession"))
+        .bearer_auth(&current.refresh_jwt)
         .send()
         .await
-    {
-        Ok(response) => {
-            if response.status().is_success() {
-                let refresh_response: RefreshResponse = response.json().await.ok()?;
-                let session = BskySession {
-                    access_jwt: refresh_response.access_jwt,
-                    refresh_jwt: refresh_response.refresh_jwt,
-                    did: refresh_response.did,
-                };
-                save_tokens(&session);
-                println!("Token refreshed successfully.");
-                Some(session)
-            } else {
-                println!("Failed to refresh access token: {:?}", response.text().await);
-                None
+        .map_err(|err| RefreshError::Failed(err.to_string()))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let text = response.text().await.unwrap_or_default();
+        println!("Failed to refresh access token: {} {}", status, text);
+        return if status.is_client_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS {
+            Err(RefreshError::Rejected(format!("{} {}", status, text)))
+        } else {
+            Err(RefreshError::Failed(format!("{} {}", status, text)))
+        };
+    }
+
+    let refresh_response: RefreshResponse = response.json().await.map_err(|err| RefreshError::Failed(err.to_string()))?;
+    let session = BskySession {
+        access_jwt: refresh_response.access_jwt,
+        refresh_jwt: refresh_response.refresh_jwt,
+        did: refresh_response.did,
+        pds_endpoint: current.pds_endpoint.clone(),
+        oauth: None,
+    };
+    save_tokens(account, &session);
+    println!("Token refreshed successfully.");
+    Ok(session)
+}
+
+/// Refreshes `current` the way it was obtained: OAuth sessions at the authorization server,
+/// app-password sessions through `refreshSession` on the session service.
+async fn refresh_session(
+    account: &AccountConfig,
+    endpoints: &ServiceEndpoints,
+    current: &BskySession,
+) -> Result<BskySession, RefreshError> {
+    match &current.oauth {
+        Some(binding) => refresh_oauth_session(account, current, binding).await.map_err(|err| match err {
+            OAuthError::Server { status, .. } if status.is_client_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                RefreshError::Rejected(err.to_string())
             }
-        }
-        Err(err) => {
-            println!("Error refreshing token: {:?}", err);
-            None
-        }
+            _ => RefreshError::Failed(err.to_string()),
+        }),
+        None => refresh_access_token(account, endpoints, current).await,
     }
 }
 
-pub async fn ensure_bsky_token(client: &Client, data: &web::Data<BskyState>, body: &mut String) -> Option<String> {
+pub async fn ensure_bsky_token(client: &Client, data: &BskyState, body: &mut String) -> Option<String> {
+    let endpoints = data.endpoints.lock().await.clone();
     let mut token_guard = data.token.lock().await;
     if let Some(session) = token_guard.as_ref() {
-        // Check if the token is still valid
-        if is_token_valid(&session.access_jwt).await {
+        let margin = refresh_margin(&data.account);
+        // Reuse the access token while it is comfortably inside its lifetime
+        if session.access_valid(margin) {
             println!("Using existing valid token.");
             return Some(session.access_jwt.clone());
         }
-        // Try to refresh the token
-        if let Some(new_session) = refresh_access_token(&session.refresh_jwt).await {
-            *token_guard = Some(new_session.clone());
-            println!("Token was expired and has been refreshed.");
-            return Some(new_session.access_jwt);
+        // Try to refresh the token, as long as the refresh token itself hasn't expired
+        if session.can_refresh() {
+            if let Ok(new_session) = refresh_session(&data.account, &endpoints, session).await {
+                *token_guard = Some(new_session.clone());
+                println!("Token was expired and has been refreshed.");
+                return Some(new_session.access_jwt);
+            }
         }
     }
     // If no valid token, perform login
-    match bluesky_login(client).await {
+    match bluesky_login(client, &data.account, &endpoints).await {
         Ok(session) => {
+            data.endpoints.lock().await.adopt_session(&session);
             *token_guard = Some(session.clone());
             println!("No valid token found, logged in to obtain a new token.");
             Some(session.access_jwt)
@@ -142,15 +532,287 @@ pub async fn ensure_bsky_token(client: &Client, data: &web::Data<BskyState>, bod
     }
 }
 
-async fn is_token_valid(token: &str) -> bool {
-    let client = Client::new();
-    let url = "https://bsky.social/xrpc/app.bsky.feed.getTimeline"; // Example endpoint
-    let resp = client.get(url).bearer_auth(token).send().await;
-    match resp {
-        Ok(response) => response.status().is_success(),
-        Err(e) => {
-            println!("Token validation failed: {:?}", e);
+/// How long before expiry the account's token is treated as stale, from `BLUESKY_TOKEN_REFRESH_MARGIN` (seconds).
+fn refresh_margin(account: &AccountConfig) -> Duration {
+    let secs = account
+        .setting("TOKEN_REFRESH_MARGIN")
+        .and_then(|s| s.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_REFRESH_MARGIN_SECS);
+    Duration::seconds(secs.max(0))
+}
+
+/// The timing claims of a JWT.
+#[derive(Deserialize)]
+struct JwtClaims {
+    exp: i64,
+    #[serde(default)]
+    iat: Option<i64>,
+}
+
+impl JwtClaims {
+    fn issued(&self) -> Option<DateTime<Utc>> {
+        Utc.timestamp_opt(self.iat?, 0).single()
+    }
+}
+
+/// Reads a JWT's claims without verifying its signature.
+/// The PDS is the authority on validity; this only tells us when to stop reusing a token.
+fn jwt_claims(token: &str) -> Option<JwtClaims> {
+    let payload = token.split('.').nth(1)?;
+    let decoded = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+    serde_json::from_slice(&decoded).ok()
+}
+
+/// Reads the `exp` claim from a JWT.
+pub fn jwt_expiry(token: &str) -> Option<DateTime<Utc>> {
+    Utc.timestamp_opt(jwt_claims(token)?.exp, 0).single()
+}
+
+/// A token is valid if it expires later than `margin` from now.
+/// Tokens we can't decode are treated as expired so we fall back to refresh or login.
+fn is_token_valid(expiry: Option<DateTime<Utc>>, margin: Duration) -> bool {
+    match expiry {
+        Some(exp) => exp - margin > Utc::now(),
+        None => {
+            println!("Token validation failed: could not decode expiry");
             false
         }
     }
 }
+
+/// Keeps the session in `state` fresh so widget requests never wait on auth.
+/// Sleeps until the access token is within the refresh margin, refreshes it, and backs off
+/// exponentially on failure. A rejected refresh JWT falls back to a full `bluesky_login`. When
+/// logging in needs a person (an OAuth sign-in, a sign-in code or missing credentials), it stops
+/// trying and waits for a session to show up some other way.
+pub async fn run_token_refresher(state: BskyState) {
+    let client = Client::new();
+    let mut backoff = REFRESHER_MIN_BACKOFF_SECS;
+    let mut paused = false;
+
+    loop {
+        let endpoints = state.endpoints.lock().await.clone();
+        let margin = refresh_margin(&state.account);
+
+        // Refresh JWTs are single-use, so the lock is held for the whole refresh; otherwise
+        // `ensure_bsky_token` could spend the same one and the loser would store a dead session
+        let mut token_guard = state.token.lock().await;
+        if paused && token_guard.is_none() {
+            drop(token_guard);
+            sleep(std::time::Duration::from_secs(REFRESHER_MAX_SLEEP_SECS as u64)).await;
+            continue;
+        }
+        paused = false;
+        let due = token_guard.as_ref().filter(|session| session.access_valid(margin)).map(|session| {
+            session
+                .access_expiry()
+                .map(|exp| exp - session.refresh_margin(margin) - Utc::now())
+                .unwrap_or_else(Duration::zero)
+        });
+        if let Some(due) = due {
+            drop(token_guard);
+            let wait = due.num_seconds().clamp(1, REFRESHER_MAX_SLEEP_SECS) as u64;
+            sleep(std::time::Duration::from_secs(wait)).await;
+            continue;
+        }
+
+        let refreshed = match token_guard.as_ref() {
+            Some(session) if session.can_refresh() => Some(refresh_session(&state.account, &endpoints, session).await),
+            _ => None,
+        };
+        let result = match refreshed {
+            Some(Ok(new_session)) => Ok(new_session),
+            Some(Err(RefreshError::Failed(msg))) => Err(msg),
+            Some(Err(RefreshError::Rejected(_))) | None => {
+                if let Some(Err(RefreshError::Rejected(msg))) = &refreshed {
+                    println!("Background refresh rejected ({}), logging in again.", msg);
+                }
+                match bluesky_login(&client, &state.account, &endpoints).await {
+                    Err(e) if e.needs_person() => {
+                        // Whatever session there was is dead; wait for a sign-in instead of retrying
+                        *token_guard = None;
+                        drop(token_guard);
+                        println!("Background token refresh paused for account {}: {}", state.account.name, e);
+                        paused = true;
+                        continue;
+                    }
+                    other => other.map_err(|e| e.to_string()),
+                }
+            }
+        };
+
+        match result {
+            Ok(new_session) => {
+                state.endpoints.lock().await.adopt_session(&new_session);
+                *token_guard = Some(new_session);
+                drop(token_guard);
+                println!("Background token refresh succeeded for account {}.", state.account.name);
+                backoff = REFRESHER_MIN_BACKOFF_SECS;
+                // A token we can't read the expiry of looks due at once; never refresh back to back
+                sleep(std::time::Duration::from_secs(REFRESHER_MIN_BACKOFF_SECS)).await;
+            }
+            Err(e) => {
+                drop(token_guard);
+                println!(
+                    "Background token refresh failed for account {}, retrying in {}s: {}",
+                    state.account.name, backoff, e
+                );
+                sleep(std::time::Duration::from_secs(backoff)).await;
+                backoff = (backoff * 2).min(REFRESHER_MAX_BACKOFF_SECS);
+            }
+        }
+    }
+}
+
+/// Sends an XRPC GET for `method`. With a token it goes to the account's PDS, which proxies it
+/// to the AppView (with a DPoP proof for OAuth sessions); without one it goes to the public AppView.
+pub async fn xrpc_get<Q: Serialize + ?Sized>(
+    client: &Client,
+    state: &BskyState,
+    method: &str,
+    token: Option<&str>,
+    query: &Q,
+) -> Result<reqwest::Response, Box<dyn std::error::Error>> {
+    let endpoints = state.endpoints.lock().await.clone();
+    let Some(token) = token else {
+        return Ok(client.get(endpoints.public_xrpc(method)).query(query).send().await?);
+    };
+    let request = client.get(endpoints.pds_xrpc(method)).query(query);
+    send_authenticated(client, state, &endpoints, token, request).await
+}
+
+/// Sends an XRPC procedure (POST with a JSON body) for `method` through the account's PDS.
+/// Procedures change account state, so they always need a token.
+pub async fn xrpc_post(
+    client: &Client,
+    state: &BskyState,
+    method: &str,
+    token: &str,
+    body: &serde_json::Value,
+) -> Result<reqwest::Response, Box<dyn std::error::Error>> {
+    let endpoints = state.endpoints.lock().await.clone();
+    let request = client.post(endpoints.pds_xrpc(method)).json(body);
+    send_authenticated(client, state, &endpoints, token, request).await
+}
+
+/// Adds the AppView proxy header and the session's credentials to a PDS request and sends it.
+/// Access tokens are only good at the PDS, so the AppView is always reached through it.
+async fn send_authenticated(
+    client: &Client,
+    state: &BskyState,
+    endpoints: &ServiceEndpoints,
+    token: &str,
+    mut request: reqwest::RequestBuilder,
+) -> Result<reqwest::Response, Box<dyn std::error::Error>> {
+    if let Some(proxy) = &endpoints.appview_proxy {
+        request = request.header("atproto-proxy", proxy);
+    }
+    match oauth_binding(state, token).await {
+        Some(binding) => Ok(dpop_request(client, state, &binding, token, request.build()?).await?),
+        None => Ok(request.bearer_auth(token).send().await?),
+    }
+}
+
+/// The OAuth binding of the session `token` belongs to, if it is an OAuth session.
+async fn oauth_binding(state: &BskyState, token: &str) -> Option<OAuthBinding> {
+    state
+        .token
+        .lock()
+        .await
+        .as_ref()
+        .filter(|s| s.access_jwt == token)
+        .and_then(|s| s.oauth.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use std::env;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn jwt(claims: serde_json::Value) -> String {
+        format!("e30.{}.sig", URL_SAFE_NO_PAD.encode(claims.to_string()))
+    }
+
+    fn session(access_jwt: String) -> BskySession {
+        BskySession {
+            access_jwt,
+            refresh_jwt: String::new(),
+            did: "did:plc:test".to_string(),
+            pds_endpoint: None,
+            oauth: None,
+        }
+    }
+
+    #[test]
+    fn refresh_margin_is_capped_at_half_the_token_lifetime() {
+        let now = Utc::now().timestamp();
+        let fresh = session(jwt(json!({ "iat": now, "exp": now + 120 })));
+        assert_eq!(fresh.refresh_margin(Duration::seconds(600)), Duration::seconds(60));
+        assert_eq!(fresh.refresh_margin(Duration::seconds(30)), Duration::seconds(30));
+        assert!(fresh.access_valid(Duration::seconds(600)));
+
+        // Without `iat` the lifetime is unknown, so the configured margin stands
+        let no_iat = session(jwt(json!({ "exp": now + 120 })));
+        assert_eq!(no_iat.refresh_margin(Duration::seconds(600)), Duration::seconds(600));
+        assert!(!no_iat.access_valid(Duration::seconds(600)));
+    }
+
+    #[test]
+    fn discovered_pds_does_not_move_session_management() {
+        let mut endpoints = ServiceEndpoints::for_account(&AccountConfig::named("endpoints-test"));
+        let mut discovered = session(String::new());
+        discovered.pds_endpoint = Some("https://pds.example.com".to_string());
+        endpoints.adopt_session(&discovered);
+        assert_eq!(
+            endpoints.pds_xrpc("app.bsky.feed.getTimeline"),
+            "https://pds.example.com/xrpc/app.bsky.feed.getTimeline"
+        );
+        assert_eq!(
+            endpoints.session_xrpc("com.atproto.server.refreshSession"),
+            format!("{}/xrpc/com.atproto.server.refreshSession", endpoints.session_service)
+        );
+        assert_ne!(endpoints.session_service, "https://pds.example.com");
+    }
+
+    /// A `createSession` endpoint that always wants an emailed sign-in code, counting its calls.
+    async fn mock_create_session(calls: web::Data<AtomicUsize>) -> HttpResponse {
+        calls.fetch_add(1, Ordering::SeqCst);
+        HttpResponse::Unauthorized().json(json!({ "error": "AuthFactorTokenRequired", "message": "A sign in code has been sent" }))
+    }
+
+    #[actix_web::test]
+    async fn logins_needing_a_sign_in_code_are_not_retried_until_secrets_reload() {
+        let calls = web::Data::new(AtomicUsize::new(0));
+        let server_calls = calls.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(server_calls.clone())
+                .route("/xrpc/com.atproto.server.createSession", web::post().to(mock_create_session))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let base = format!("http://{}", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+
+        env::set_var("BLUESKY_TWO_FACTOR_TEST_USERNAME", "alice.test");
+        env::set_var("BLUESKY_TWO_FACTOR_TEST_PASSWORD", "app-password");
+        let account = AccountConfig::named("two-factor-test");
+        let mut endpoints = ServiceEndpoints::for_account(&account);
+        endpoints.session_service = base;
+        let client = Client::new();
+
+        for _ in 0..3 {
+            let result = bluesky_login(&client, &account, &endpoints).await;
+            assert!(matches!(result, Err(LoginError::AuthFactorTokenRequired)));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1, "only the first login reaches the server");
+
+        secrets::reload();
+        assert!(bluesky_login(&client, &account, &endpoints).await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 2, "a reload allows one more attempt");
+    }
+}
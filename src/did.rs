@@ -0,0 +1,51 @@
+use reqwest::Client;
+use serde::Deserialize;
+
+use crate::account::AccountConfig;
+
+const DEFAULT_PLC_DIRECTORY: &str = "https://plc.directory";
+
+/// The parts of a DID document we care about.
+#[derive(Debug, Deserialize)]
+struct DidDocument {
+    #[serde(default)]
+    service: Vec<DidService>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DidService {
+    id: String,
+    #[serde(rename = "serviceEndpoint")]
+    service_endpoint: String,
+}
+
+/// Where to fetch the DID document for `did`.
+/// did:plc goes through the account's PLC directory (`BLUESKY_PLC_DIRECTORY`), did:web through the well-known path.
+fn did_document_url(account: &AccountConfig, did: &str) -> Option<String> {
+    if did.starts_with("did:plc:") {
+        let directory = account
+            .setting("PLC_DIRECTORY")
+            .unwrap_or_else(|| DEFAULT_PLC_DIRECTORY.to_string());
+        Some(format!("{}/{}", directory.trim_end_matches('/'), did))
+    } else if let Some(host) = did.strip_prefix("did:web:") {
+        // atproto only allows hostname-level did:web, with the port percent-encoded
+        if host.is_empty() || host.contains(':') {
+            return None;
+        }
+        Some(format!("https://{}/.well-known/did.json", host.replace("%3A", ":")))
+    } else {
+        None
+    }
+}
+
+/// Resolves the `#atproto_pds` service endpoint listed in the account's DID document.
+pub async fn resolve_pds_endpoint(client: &Client, account: &AccountConfig, did: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let url = did_document_url(account, did).ok_or_else(|| format!("unsupported DID method: {}", did))?;
+    let doc: DidDocument = client.get(url).send().await?.error_for_status()?.json().await?;
+
+    doc.service
+        .into_iter()
+        .find(|s| s.id == "#atproto_pds" || s.id == format!("{}#atproto_pds", did))
+        .map(|s| s.service_endpoint.trim_end_matches('/').to_string())
+        .ok_or_else(|| format!("no #atproto_pds service in DID document for {}", did).into())
+}
@@ -0,0 +1,99 @@
+use reqwest::Client;
+use serde::Deserialize;
+
+use crate::actors::fetch_actor_list;
+use crate::aturi::RecordRef;
+use crate::auth::BskyState;
+use crate::paging::{collect_page, PageRequest};
+use crate::params::Params;
+use crate::post::{BskyPost, BskyProfile};
+
+/// Largest page `getQuotes` will return.
+const QUOTES_PAGE_SIZE: usize = 100;
+
+/// Which reaction to a post a widget drills into.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reaction {
+    Likes,
+    Reposts,
+}
+
+impl Reaction {
+    /// How the reaction reads in "Liked by 12 accounts".
+    pub fn label(self) -> &'static str {
+        match self {
+            Reaction::Likes => "liked",
+            Reaction::Reposts => "reposted",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BskyGetQuotesResponse {
+    #[serde(default)]
+    posts: Vec<BskyPost>,
+    #[serde(default)]
+    cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BskyLike {
+    actor: BskyProfile,
+}
+
+#[derive(Debug, Deserialize)]
+struct BskyGetLikesResponse {
+    #[serde(default)]
+    likes: Vec<BskyLike>,
+    #[serde(default)]
+    cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BskyGetRepostedByResponse {
+    #[serde(default, rename = "repostedBy")]
+    reposted_by: Vec<BskyProfile>,
+    #[serde(default)]
+    cursor: Option<String>,
+}
+
+/// Fetches posts quoting `post` via `app.bsky.feed.getQuotes`, newest first.
+pub async fn fetch_quotes(
+    client: &Client,
+    state: &BskyState,
+    token: Option<&str>,
+    post: &RecordRef,
+    params: &Params,
+) -> Result<(Vec<BskyPost>, Option<String>), Box<dyn std::error::Error>> {
+    let query = [("uri", post.resolve(client, state, token).await?)];
+    let request = PageRequest {
+        method: "app.bsky.feed.getQuotes",
+        token,
+        query: &query,
+        page_size: QUOTES_PAGE_SIZE,
+    };
+    let extract = |result: BskyGetQuotesResponse| (result.posts, result.cursor);
+    collect_page(client, state, &request, params, extract, |_| true).await
+}
+
+/// Fetches the accounts that liked (`getLikes`) or reposted (`getRepostedBy`) `post`.
+pub async fn fetch_reactors(
+    client: &Client,
+    state: &BskyState,
+    token: Option<&str>,
+    post: &RecordRef,
+    reaction: Reaction,
+    params: &Params,
+) -> Result<(Vec<BskyProfile>, Option<String>), Box<dyn std::error::Error>> {
+    let query = vec![("uri", post.resolve(client, state, token).await?)];
+    match reaction {
+        Reaction::Likes => {
+            let extract = |r: BskyGetLikesResponse| (r.likes.into_iter().map(|like| like.actor).collect(), r.cursor);
+            fetch_actor_list(client, state, token, "app.bsky.feed.getLikes", query, params, extract).await
+        }
+        Reaction::Reposts => {
+            let extract = |r: BskyGetRepostedByResponse| (r.reposted_by, r.cursor);
+            fetch_actor_list(client, state, token, "app.bsky.feed.getRepostedBy", query, params, extract).await
+        }
+    }
+}
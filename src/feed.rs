@@ -0,0 +1,175 @@
+use reqwest::Client;
+
+use crate::aturi::RecordRef;
+use crate::auth::{xrpc_get, BskyState};
+use crate::paging::{collect_page, PageRequest};
+use crate::params::Params;
+use crate::post::{BskyFeedResponse, BskyFeedViewPost, BskyGetListResponse, BskyPost};
+
+/// Largest page the feed endpoints will return.
+pub const FEED_PAGE_SIZE: usize = 100;
+
+/// `filter` values accepted by `app.bsky.feed.getAuthorFeed`.
+pub const AUTHOR_FEED_FILTERS: [&str; 5] = [
+    "posts_with_replies",
+    "posts_no_replies",
+    "posts_with_media",
+    "posts_and_author_threads",
+    "posts_with_video",
+];
+
+/// Fetches an account's recent posts and reposts via `app.bsky.feed.getAuthorFeed`.
+pub async fn fetch_author_feed(
+    client: &Client,
+    state: &BskyState,
+    token: Option<&str>,
+    actor: &str,
+    filter: Option<&str>,
+    params: &Params,
+) -> Result<(Vec<BskyPost>, Option<String>), Box<dyn std::error::Error>> {
+    let mut query = vec![("actor", actor.trim_start_matches('@').to_string())];
+    if let Some(filter) = filter {
+        query.push(("filter", filter.to_string()));
+    }
+    fetch_feed(client, state, token, "app.bsky.feed.getAuthorFeed", query, params).await
+}
+
+/// Fetches an account's latest `count` top-level posts (and reposts) in a single request,
+/// without touching the widget's paging parameters.
+pub async fn fetch_recent_posts(
+    client: &Client,
+    state: &BskyState,
+    token: Option<&str>,
+    actor: &str,
+    count: usize,
+) -> Result<Vec<BskyPost>, Box<dyn std::error::Error>> {
+    let query = [
+        ("actor", actor.trim_start_matches('@').to_string()),
+        ("filter", "posts_no_replies".to_string()),
+        ("limit", count.clamp(1, FEED_PAGE_SIZE).to_string()),
+    ];
+    let response = xrpc_get(client, state, "app.bsky.feed.getAuthorFeed", token, &query).await?;
+    if !response.status().is_success() {
+        return Err(Box::new(response.error_for_status().unwrap_err()));
+    }
+    let result: BskyFeedResponse = serde_json::from_str(&response.text().await?)?;
+    Ok(result.feed.into_iter().take(count).map(BskyFeedViewPost::into_post).collect())
+}
+
+/// Fetches a custom feed generator's posts via `app.bsky.feed.getFeed`.
+pub async fn fetch_custom_feed(
+    client: &Client,
+    state: &BskyState,
+    token: Option<&str>,
+    feed: &RecordRef,
+    params: &Params,
+) -> Result<(Vec<BskyPost>, Option<String>), Box<dyn std::error::Error>> {
+    let feed_uri = feed.resolve(client, state, token).await?;
+    fetch_feed(client, state, token, "app.bsky.feed.getFeed", vec![("feed", feed_uri)], params).await
+}
+
+/// Fetches the posts of a list's members via `app.bsky.feed.getListFeed`. Returns the posts, the
+/// cursor, and the list's name when the widget has no title of its own.
+pub async fn fetch_list_feed(
+    client: &Client,
+    state: &BskyState,
+    token: Option<&str>,
+    list: &RecordRef,
+    params: &Params,
+) -> Result<(Vec<BskyPost>, Option<String>, Option<String>), Box<dyn std::error::Error>> {
+    let list_uri = list.resolve(client, state, token).await?;
+    let (posts, cursor) = fetch_feed(
+        client,
+        state,
+        token,
+        "app.bsky.feed.getListFeed",
+        vec![("list", list_uri.clone())],
+        params,
+    )
+    .await?;
+    let title = match params.title {
+        Some(_) => None,
+        None => fetch_list_name(client, state, token, list_uri)
+            .await
+            .map_err(|e| println!("Could not fetch list name: {}", e))
+            .ok(),
+    };
+    Ok((posts, cursor, title))
+}
+
+/// Looks up a list's name via `app.bsky.graph.getList`.
+async fn fetch_list_name(
+    client: &Client,
+    state: &BskyState,
+    token: Option<&str>,
+    list_uri: String,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let query = [("list", list_uri), ("limit", "1".to_string())];
+    let response = xrpc_get(client, state, "app.bsky.graph.getList", token, &query).await?;
+    if !response.status().is_success() {
+        return Err(Box::new(response.error_for_status().unwrap_err()));
+    }
+    let result: BskyGetListResponse = serde_json::from_str(&response.text().await?)?;
+    Ok(result.list.name)
+}
+
+/// Fetches the signed-in account's home timeline via `app.bsky.feed.getTimeline`,
+/// dropping reposts and replies as the `hide-*` parameters ask.
+pub async fn fetch_timeline(
+    client: &Client,
+    state: &BskyState,
+    token: Option<&str>,
+    params: &Params,
+) -> Result<(Vec<BskyPost>, Option<String>), Box<dyn std::error::Error>> {
+    if token.is_none() {
+        return Err("the timeline needs a signed-in account".into());
+    }
+    let viewer_did = state
+        .token
+        .lock()
+        .await
+        .as_ref()
+        .map(|session| session.did.clone())
+        .ok_or("no Bluesky session")?;
+
+    let keep = |item: &BskyFeedViewPost| {
+        !(params.hide_reposts && item.is_repost()
+            || params.hide_replies && item.is_reply()
+            || params.hide_unfollowed_replies && !item.replies_within_follows(&viewer_did))
+    };
+    fetch_filtered_feed(client, state, token, "app.bsky.feed.getTimeline", Vec::new(), params, &keep).await
+}
+
+/// Pages through one of the feed endpoints in feed order, honouring `page`, `cursor` and `limit`
+/// the same way search does. Reposts keep their attribution.
+pub async fn fetch_feed(
+    client: &Client,
+    state: &BskyState,
+    token: Option<&str>,
+    method: &str,
+    base_query: Vec<(&'static str, String)>,
+    params: &Params,
+) -> Result<(Vec<BskyPost>, Option<String>), Box<dyn std::error::Error>> {
+    fetch_filtered_feed(client, state, token, method, base_query, params, &|_| true).await
+}
+
+/// Like `fetch_feed`, keeping only the items `keep` accepts. Filtered items don't count towards `limit`.
+async fn fetch_filtered_feed(
+    client: &Client,
+    state: &BskyState,
+    token: Option<&str>,
+    method: &str,
+    base_query: Vec<(&'static str, String)>,
+    params: &Params,
+    keep: &dyn Fn(&BskyFeedViewPost) -> bool,
+) -> Result<(Vec<BskyPost>, Option<String>), Box<dyn std::error::Error>> {
+    let request = PageRequest {
+        method,
+        token,
+        query: &base_query,
+        page_size: FEED_PAGE_SIZE,
+    };
+    let extract = |result: BskyFeedResponse| (result.feed, result.cursor);
+    let (items, cursor) = collect_page(client, state, &request, params, extract, keep).await?;
+    Ok((items.into_iter().map(BskyFeedViewPost::into_post).collect(), cursor))
+}
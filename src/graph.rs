@@ -0,0 +1,126 @@
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::actors::fetch_actor_list;
+use crate::auth::{xrpc_get, BskyState};
+use crate::params::Params;
+use crate::post::BskyProfile;
+
+/// Which side of an account's follow graph a widget lists.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FollowDirection {
+    Followers,
+    Follows,
+}
+
+impl FollowDirection {
+    pub fn name(self) -> &'static str {
+        match self {
+            FollowDirection::Followers => "followers",
+            FollowDirection::Follows => "follows",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BskyGetFollowersResponse {
+    #[serde(default)]
+    followers: Vec<BskyProfile>,
+    #[serde(default)]
+    cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BskyGetFollowsResponse {
+    #[serde(default)]
+    follows: Vec<BskyProfile>,
+    #[serde(default)]
+    cursor: Option<String>,
+}
+
+/// One member of a list ("app.bsky.graph.defs#listItemView").
+#[derive(Debug, Deserialize)]
+struct BskyListItem {
+    subject: BskyProfile,
+}
+
+#[derive(Debug, Deserialize)]
+struct BskyGetListItemsResponse {
+    #[serde(default)]
+    items: Vec<BskyListItem>,
+    #[serde(default)]
+    cursor: Option<String>,
+}
+
+/// The parts of "app.bsky.graph.defs#starterPackView" we use: its name and the list behind it.
+#[derive(Debug, Deserialize)]
+struct BskyStarterPackView {
+    #[serde(default)]
+    record: Value,
+    list: Option<BskyStarterPackList>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BskyStarterPackList {
+    uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct BskyGetStarterPackResponse {
+    #[serde(rename = "starterPack")]
+    starter_pack: BskyStarterPackView,
+}
+
+/// Fetches an account's followers (`getFollowers`) or the accounts it follows (`getFollows`), newest first.
+pub async fn fetch_follow_list(
+    client: &Client,
+    state: &BskyState,
+    token: Option<&str>,
+    actor: &str,
+    direction: FollowDirection,
+    params: &Params,
+) -> Result<(Vec<BskyProfile>, Option<String>), Box<dyn std::error::Error>> {
+    let query = vec![("actor", actor.trim_start_matches('@').to_string())];
+    match direction {
+        FollowDirection::Followers => {
+            let extract = |r: BskyGetFollowersResponse| (r.followers, r.cursor);
+            fetch_actor_list(client, state, token, "app.bsky.graph.getFollowers", query, params, extract).await
+        }
+        FollowDirection::Follows => {
+            let extract = |r: BskyGetFollowsResponse| (r.follows, r.cursor);
+            fetch_actor_list(client, state, token, "app.bsky.graph.getFollows", query, params, extract).await
+        }
+    }
+}
+
+/// Fetches a starter pack by its resolved `at://` URI via `app.bsky.graph.getStarterPack`, then
+/// pages through the members of its list. Returns the members, the cursor, and the pack's name.
+pub async fn fetch_starter_pack_members(
+    client: &Client,
+    state: &BskyState,
+    token: Option<&str>,
+    uri: &str,
+    params: &Params,
+) -> Result<(Vec<BskyProfile>, Option<String>, Option<String>), Box<dyn std::error::Error>> {
+    let response = xrpc_get(client, state, "app.bsky.graph.getStarterPack", token, &[("starterPack", uri)]).await?;
+    if !response.status().is_success() {
+        return Err(Box::new(response.error_for_status().unwrap_err()));
+    }
+    let pack: BskyGetStarterPackResponse = serde_json::from_str(&response.text().await?)?;
+    let name = pack.starter_pack.record["name"].as_str().map(str::to_string);
+    let list = pack.starter_pack.list.ok_or("starter pack has no member list")?;
+
+    let extract = |r: BskyGetListItemsResponse| (r.items.into_iter().map(|item| item.subject).collect(), r.cursor);
+    let (members, cursor) = fetch_actor_list(
+        client,
+        state,
+        token,
+        "app.bsky.graph.getList",
+        vec![("list", list.uri)],
+        params,
+        extract,
+    )
+    .await?;
+    Ok((members, cursor, name))
+}
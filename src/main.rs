@@ -2,173 +2,83 @@ use actix_web::{get, http::header, web, App, HttpResponse, HttpServer, Responder
 use dotenv::dotenv;
 use reqwest::Client;
 
-use std::collections::HashMap;
-use std::env;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::collections::{HashMap, HashSet};
 
-use chrono::{DateTime, Duration, Utc};
 use html_escape::encode_safe;
-use humantime::format_duration;
 
 mod post;
-use post::{BskyPost, BskySearchPostsResponse};
+use post::{BskyPost, BskyProfile, BskyThreadNode};
 
 mod auth;
-use auth::{ensure_bsky_token, load_tokens, BskyState};
-
-fn parse_relative_time(spec: &str) -> Option<DateTime<Utc>> {
-    if (!spec.starts_with('-')) || spec.len() < 3 {
-        return None;
-    }
-
-    // Split off the trailing unit (last char), e.g. 'd', 'h', 'm', or 's'
-    let (num_part, unit_part) = spec[1..].split_at(spec.len() - 2);
-    let digits = num_part.parse::<i64>().ok()?;
-    let unit_char = unit_part.chars().next()?;
-
-    let duration = match unit_char {
-        'd' => Duration::days(digits),
-        'h' => Duration::hours(digits),
-        'm' => Duration::minutes(digits),
-        's' => Duration::seconds(digits),
-        _ => return None,
-    };
-
-    // Return "now - duration"
-    Some(Utc::now() - duration)
+mod did;
+mod engagement;
+mod feed;
+mod graph;
+mod notifications;
+mod oauth;
+mod profile;
+mod thread;
+use auth::{ensure_bsky_token, run_token_refresher, sign_in_at_startup, AuthMode, BskyState};
+
+mod account;
+mod actors;
+mod aturi;
+mod paging;
+mod params;
+mod render;
+mod search;
+mod secrets;
+mod seen;
+mod store;
+mod tags;
+mod timespec;
+use account::Accounts;
+use actors::{build_actors_html, build_avatar_strip_html, search_actors};
+use aturi::resolve_did;
+use engagement::{fetch_quotes, fetch_reactors, Reaction};
+use feed::{fetch_author_feed, fetch_custom_feed, fetch_list_feed, fetch_timeline};
+use graph::{fetch_follow_list, fetch_starter_pack_members};
+use notifications::{build_notifications_html, fetch_notifications, NotificationGroup};
+use params::{parse_params, Params, Source};
+use profile::{build_profile_html, fetch_profile};
+use render::{build_html_header, build_posts_html};
+use search::{base_query, search_bluesky_posts};
+use thread::{build_thread_html, fetch_thread};
+
+/// What a source fetched, ready to render, plus the cursor for the next page.
+enum Content {
+    /// Posts, with a title of their own for list feeds.
+    Posts {
+        posts: Vec<BskyPost>,
+        cursor: Option<String>,
+        title: Option<String>,
+    },
+    Notifications(Vec<NotificationGroup>, Option<String>),
+    Thread(BskyThreadNode),
+    Profile(Box<BskyProfile>, Vec<BskyPost>),
+    Actors(Vec<BskyProfile>, Option<String>),
+    AvatarStrip(Vec<BskyProfile>, Option<String>, Reaction),
+    /// Member lists, with the members that are new since the last render and a title of their own.
+    Members {
+        members: Vec<BskyProfile>,
+        cursor: Option<String>,
+        new: HashSet<String>,
+        title: Option<String>,
+    },
 }
 
-fn format_relative_time(datetime: &str) -> String {
-    let parsed_datetime = DateTime::parse_from_rfc3339(datetime).unwrap_or_else(|_| Utc::now().into());
-    let secs = Utc::now().signed_duration_since(parsed_datetime).num_seconds();
-    let duration = std::time::Duration::from_secs(secs.max(0) as u64);
-    format!("{} ago", format_duration(duration))
-}
-
-/// Searches Bluesky posts by a naive hashtag approach.
-async fn search_bluesky_posts(
-    client: &Client,
-    token: &str,
-    hashtags: &[String],
-    max_posts: usize,
-    maybe_since_time: Option<DateTime<Utc>>,
-    sort: &str,
-) -> Result<Vec<BskyPost>, Box<dyn std::error::Error>> {
-    let base_url = env::var("BLUESKY_BASE_URL").unwrap_or_else(|_| "https://bsky.social".to_string());
-
-    // e.g. "#rust #actix #web"
-    let base_query = hashtags.iter().map(|tag| format!("#{}", tag)).collect::<Vec<_>>().join(" ");
-
-    // If we got a valid DateTime, prepend it as `since:2025-01-05T12:34:56Z`
-    let joined_query = if let Some(since_dt) = maybe_since_time {
-        let timestamp_str = since_dt.to_rfc3339(); // e.g. "2025-01-05T12:34:56Z"
-        format!("since:{} {}", timestamp_str, base_query)
-    } else {
-        // If there's no valid 'since' param, just use the base query
-        base_query
-    };
-
-    let limit = max_posts.min(50);
-    let url = format!("{}/xrpc/app.bsky.feed.searchPosts", base_url);
-
-    let resp = client
-        .get(url)
-        .bearer_auth(token)
-        .query(&[("q", &joined_query), ("limit", &limit.to_string()), ("sort", &sort.to_string())])
-        .send()
-        .await;
-
-    match resp {
-        Ok(response) => {
-            if response.status().is_success() {
-                let text = response.text().await?;
-                let mut result: BskySearchPostsResponse = serde_json::from_str(&text)?;
-                result.posts.sort_by_key(|p| p.indexed_at.clone());
-                result.posts.reverse();
-                Ok(result.posts)
-            } else {
-                Err(Box::new(response.error_for_status().unwrap_err()))
-            }
+impl Content {
+    /// A title the content brings along, used when the widget doesn't set one.
+    fn title(&self) -> Option<String> {
+        match self {
+            Content::Posts { title, .. } | Content::Members { title, .. } => title.clone(),
+            _ => None,
         }
-        Err(err) => Err(Box::new(err)),
-    }
-}
-
-struct Params {
-    tags: Vec<String>,
-    limit: usize,
-    debug: bool,
-    text_color: String,
-    author_color: String,
-    text_hover_color: String,
-    author_hover_color: String,
-    text_visited_color: String,
-    maybe_since_time: Option<DateTime<Utc>>,
-    sort: String,
-    title: String,
-    collapse_after: usize,
-    hide_stats: bool,
-    hide_datetime: bool,
-    hide_author: bool,
-}
-
-fn parse_params(query: &HashMap<String, String>) -> Params {
-    let tags_param = query.get("tags").cloned().unwrap_or_default();
-    let limit = query.get("limit").and_then(|s| s.parse::<usize>().ok()).unwrap_or(10);
-    let debug = query.get("debug").and_then(|s| s.parse::<bool>().ok()).unwrap_or(false);
-    let text_color = query.get("text-color").cloned().unwrap_or("000000".to_string());
-    let author_color = query.get("author-color").cloned().unwrap_or("666".to_string());
-    let text_hover_color = query.get("text-hover-color").cloned().unwrap_or("000000".to_string());
-    let author_hover_color = query.get("author-hover-color").cloned().unwrap_or("666".to_string());
-    let text_visited_color = query.get("text-visited-color").cloned().unwrap_or("666".to_string());
-    let since_param = query.get("since").cloned().unwrap_or_default();
-    let maybe_since_time = if !since_param.is_empty() {
-        parse_relative_time(&since_param)
-    } else {
-        None
-    };
-
-    let sort = query.get("sort").cloned().unwrap_or("latest".to_string());
-    let title = query.get("title").cloned().unwrap_or("Bluesky".to_string());
-    let collapse_after = query.get("collapse-after").and_then(|s| s.parse::<usize>().ok()).unwrap_or(5);
-    let hide_stats = query.get("hide-stats").and_then(|s| s.parse::<bool>().ok()).unwrap_or(false);
-    let hide_datetime = query.get("hide-datetime").and_then(|s| s.parse::<bool>().ok()).unwrap_or(false);
-    let hide_author = query.get("hide-author").and_then(|s| s.parse::<bool>().ok()).unwrap_or(false);
-
-    let tags: Vec<String> = tags_param
-        .split(',')
-        .filter_map(|s| {
-            let trimmed = s.trim();
-            if trimmed.is_empty() {
-                None
-            } else {
-                Some(trimmed.to_string())
-            }
-        })
-        .collect();
-
-    Params {
-        tags,
-        limit,
-        debug,
-        text_color,
-        author_color,
-        text_hover_color,
-        author_hover_color,
-        text_visited_color,
-        maybe_since_time,
-        sort,
-        title,
-        collapse_after,
-        hide_stats,
-        hide_datetime,
-        hide_author,
     }
 }
 
 #[get("/")]
-async fn index(query: web::Query<HashMap<String, String>>, data: web::Data<BskyState>) -> impl Responder {
+async fn index(query: web::Query<HashMap<String, String>>, accounts: web::Data<Accounts>) -> impl Responder {
     let params = parse_params(&query);
     let mut body = build_html_header(&params);
 
@@ -176,109 +86,132 @@ async fn index(query: web::Query<HashMap<String, String>>, data: web::Data<BskyS
         show_debug_params(&query, &mut body);
     }
 
-    if params.tags.is_empty() {
-        body.push_str("<p>No tags specified. Try ?tags=rust,actix&limit=5</p>");
-        return widget_response(body, &params.title);
+    let Some(data) = accounts.get(params.account.as_deref()) else {
+        body.push_str(&format!(
+            "<p>Unknown account: {}. Add it to BLUESKY_ACCOUNTS.</p>",
+            encode_safe(params.account.as_deref().unwrap_or_default())
+        ));
+        return widget_response(body, params.widget_title());
+    };
+
+    if !params.errors.is_empty() {
+        for error in &params.errors {
+            body.push_str(&format!("<p>{}</p>", encode_safe(error)));
+        }
+        return widget_response(body, params.widget_title());
+    }
+
+    if matches!(params.source, Source::Search) && params.tags.is_empty() && base_query(&params).is_empty() {
+        body.push_str("<p>Nothing to search for. Try ?tags=rust,actix&limit=5, ?q=rust&domain=github.com or ?actor=bsky.app</p>");
+        return widget_response(body, params.widget_title());
     }
 
     let client = Client::new();
-    let token = match ensure_bsky_token(&client, &data, &mut body).await {
-        Some(t) => t,
-        None => return widget_response(body, &params.title),
+    // Public mode reads from the public AppView, so there is no token to fetch or retry
+    let public = AuthMode::for_account(&data.account) == AuthMode::Public;
+    let token = if public {
+        None
+    } else {
+        match ensure_bsky_token(&client, data, &mut body).await {
+            Some(t) => Some(t),
+            None => return widget_response(body, params.widget_title()),
+        }
     };
 
-    match search_bluesky_posts(&client, &token, &params.tags, params.limit, params.maybe_since_time, &params.sort).await {
-        Ok(posts) => build_posts_html(&posts, &mut body, &params),
+    let mut content_title = None;
+    match fetch_content(&client, data, token.as_deref(), &params).await {
+        Ok(content) => {
+            content_title = content.title();
+            render_content(content, &mut body, &params);
+        }
+        Err(e) if public => body.push_str(&format!("<p>Error fetching posts: {}</p>", e)),
         Err(e) => {
             // Try to regenerate the token and retry the request
-            if let Some(new_token) = ensure_bsky_token(&client, &data, &mut body).await {
-                match search_bluesky_posts(
-                    &client,
-                    &new_token,
-                    &params.tags,
-                    params.limit,
-                    params.maybe_since_time,
-                    &params.sort,
-                )
-                .await
-                {
-                    Ok(posts) => build_posts_html(&posts, &mut body, &params),
-                    Err(e) => body.push_str(&format!("<p>Error searching posts: {}</p>", e)),
+            if let Some(new_token) = ensure_bsky_token(&client, data, &mut body).await {
+                match fetch_content(&client, data, Some(&new_token), &params).await {
+                    Ok(content) => {
+                        content_title = content.title();
+                        render_content(content, &mut body, &params);
+                    }
+                    Err(e) => body.push_str(&format!("<p>Error fetching posts: {}</p>", e)),
                 }
             } else {
-                body.push_str(&format!("<p>Error searching posts: {}</p>", e));
+                body.push_str(&format!("<p>Error fetching posts: {}</p>", e));
             }
         }
     }
 
-    widget_response(body, &params.title)
+    // Lists and starter packs title themselves unless the widget says otherwise
+    if let (None, Some(title)) = (&params.title, &content_title) {
+        return widget_response(body, title);
+    }
+
+    widget_response(body, params.widget_title())
 }
 
-fn build_html_header(params: &Params) -> String {
-    format!(
-        r#"<!DOCTYPE html>
-    <html>
-    <head>
-        <meta charset="utf-8"/>
-        <title>Bluesky Hashtag Viewer</title>
-        <style>
-            .post-container {{
-                margin-bottom: 1em;
-                padding: 0.5em;
-                text-align: left;
-            }}
-            .post-text {{
-                margin: 0;
-                font-size: 1em;
-            }}
-            .post-text a {{
-                color: #{text_color};
-                text-decoration: none;
-            }}
-            .post-text a:hover {{
-                color: #{text_hover_color};
-                text-decoration: none;
-            }}
-            .post-text a:visited {{
-                color: #{text_visited_color};
-                text-decoration: none;
-            }}
-            .post-author {{
-                margin: 0.25em 0 0 0;
-                font-size: 0.85em;
-                color: #{author_color};
-            }}
-            .post-author a {{
-                color: inherit;
-                text-decoration: none;
-            }}
-            .post-author a:hover {{
-                color: #{author_hover_color};
-                text-decoration: none;
-            }}
-            .post-stats {{
-                margin: 0.25em 0 0 0;
-                font-size: 0.85em;
-                color: #{author_color};
-            }}
-            .post-stats a {{
-                color: inherit;
-                text-decoration: none;
-            }}
-            .post-stats a:hover {{
-                color: #{author_hover_color};
-                text-decoration: none;
-            }}
-        </style>
-    </head>
-    <body>
-    "#,
-        text_color = params.text_color,
-        text_hover_color = params.text_hover_color,
-        text_visited_color = params.text_visited_color,
-        author_color = params.author_color,
-        author_hover_color = params.author_hover_color
-    )
+/// Fetches the page of content for the widget's source.
+async fn fetch_content(
+    client: &Client,
+    state: &BskyState,
+    token: Option<&str>,
+    params: &Params,
+) -> Result<Content, Box<dyn std::error::Error>> {
+    let (posts, cursor) = match &params.source {
+        Source::Search => search_bluesky_posts(client, state, token, params).await?,
+        Source::AuthorFeed { actor, filter } => fetch_author_feed(client, state, token, actor, filter.as_deref(), params).await?,
+        Source::CustomFeed(feed) => fetch_custom_feed(client, state, token, feed, params).await?,
+        Source::ListFeed(list) => {
+            let (posts, cursor, title) = fetch_list_feed(client, state, token, list, params).await?;
+            return Ok(Content::Posts { posts, cursor, title });
+        }
+        Source::Timeline => fetch_timeline(client, state, token, params).await?,
+        Source::Quotes(post) => fetch_quotes(client, state, token, post, params).await?,
+        Source::Notifications => {
+            let (groups, cursor) = fetch_notifications(client, state, token, params).await?;
+            return Ok(Content::Notifications(groups, cursor));
+        }
+        Source::Thread(post) => return Ok(Content::Thread(fetch_thread(client, state, token, post, params).await?)),
+        Source::Profile(actor) => {
+            let (profile, posts) = fetch_profile(client, state, token, actor, params).await?;
+            return Ok(Content::Profile(Box::new(profile), posts));
+        }
+        Source::Actors { q, typeahead } => {
+            let (actors, cursor) = search_actors(client, state, token, q, *typeahead, params).await?;
+            return Ok(Content::Actors(actors, cursor));
+        }
+        Source::Reactors(post, reaction) => {
+            let (actors, cursor) = fetch_reactors(client, state, token, post, *reaction, params).await?;
+            return Ok(Content::AvatarStrip(actors, cursor, *reaction));
+        }
+        Source::FollowList(actor, direction) => {
+            // Keyed by DID, so `@alice.bsky.social` and `Alice.bsky.social` share their history
+            let did = resolve_did(client, state, token, actor).await?;
+            let (members, cursor) = fetch_follow_list(client, state, token, &did, *direction, params).await?;
+            let new = new_members(state, &format!("{}:{}", direction.name(), did), &members, params).await;
+            return Ok(Content::Members {
+                members,
+                cursor,
+                new,
+                title: None,
+            });
+        }
+        Source::StarterPack(pack) => {
+            let uri = pack.resolve(client, state, token).await?;
+            let (members, cursor, title) = fetch_starter_pack_members(client, state, token, &uri, params).await?;
+            let new = new_members(state, &uri, &members, params).await;
+            return Ok(Content::Members {
+                members,
+                cursor,
+                new,
+                title,
+            });
+        }
+    };
+    Ok(Content::Posts {
+        posts,
+        cursor,
+        title: None,
+    })
 }
 
 fn show_debug_params(query: &HashMap<String, String>, body: &mut String) {
@@ -288,63 +221,59 @@ fn show_debug_params(query: &HashMap<String, String>, body: &mut String) {
     }
 }
 
-fn build_posts_html(posts: &[BskyPost], body: &mut String, params: &Params) {
-    if posts.is_empty() {
-        body.push_str("<p>No posts found for those hashtags.</p>");
-    } else {
-        body.push_str(&format!(
-            r#"<ul class="list collapsible-container" data-collapse-after="{}">"#,
-            params.collapse_after
-        ));
-        for post in posts {
-            let post_text = post.record.text.as_deref().unwrap_or("<no text>");
-            let escaped_post_text = encode_safe(&post_text);
-            let author_handle = post.author.as_ref().and_then(|a| a.handle.clone()).unwrap_or_default();
-            let rkey = post.uri.split('/').last().unwrap_or("");
-            let post_link = format!("https://bsky.app/profile/{}/post/{}", author_handle, rkey);
-            let author_link = format!("https://bsky.app/profile/{}", author_handle);
-            let created_at = post.record.created_at.as_deref().unwrap_or("<unknown date>");
-            let relative_time = format_relative_time(created_at);
-            let like_count = post.like_count.unwrap_or(0);
-            let quote_count = post.quote_count.unwrap_or(0);
-            let reply_count = post.reply_count.unwrap_or(0);
-            let repost_count = post.repost_count.unwrap_or(0);
-
-            body.push_str(&format!(
-                r#"<li class="post-container">
-                     <p class="post-text"><a href="{}" target="_blank">{}</a></p>"#,
-                post_link, escaped_post_text
-            ));
-
-            if !params.hide_author || !params.hide_datetime {
-                body.push_str(r#"<p class="post-author">"#);
-                if !params.hide_author {
-                    body.push_str(&format!(r#"<a href="{}" target="_blank">{}</a>"#, author_link, author_handle));
-                }
-                if !params.hide_author && !params.hide_datetime {
-                    body.push_str("&nbsp;&middot;&nbsp;");
-                }
-                if !params.hide_datetime {
-                    body.push_str(&format!("{} ({})", created_at, relative_time));
-                }
-                body.push_str("</p>");
-            }
-
-            if !params.hide_stats {
-                body.push_str(&format!(
-                    r#"<p class="post-stats">
-                       Likes: {} &nbsp;&middot;&nbsp;
-                       Quotes: {} &nbsp;&middot;&nbsp;
-                       Replies: {} &nbsp;&middot;&nbsp;
-                       Reposts: {}
-                       </p>"#,
-                    like_count, quote_count, reply_count, repost_count
-                ));
-            }
+/// Which of `members` weren't in this member list last time it rendered. Only the first page is
+/// tracked, since that's where newcomers show up. Widgets with different limits see different
+/// members, so each limit is remembered separately.
+async fn new_members(state: &BskyState, list: &str, members: &[BskyProfile], params: &Params) -> HashSet<String> {
+    if params.page > 1 || params.cursor.is_some() {
+        return HashSet::new();
+    }
+    let key = format!("{}:{}:{}", state.account.name, list, params.limit);
+    let dids: Vec<String> = members.iter().map(|m| m.did.clone()).collect();
+    web::block(move || seen::new_since_last_render(&key, &dids))
+        .await
+        .unwrap_or_else(|e| {
+            println!("Could not compare members with the last render: {}", e);
+            HashSet::new()
+        })
+}
 
-            body.push_str("</li>");
+/// Renders fetched content, plus the cursor for the next page when debugging.
+fn render_content(content: Content, body: &mut String, params: &Params) {
+    let cursor = match content {
+        Content::Posts { posts, cursor, .. } => {
+            build_posts_html(&posts, body, params);
+            cursor
+        }
+        Content::Notifications(groups, cursor) => {
+            build_notifications_html(&groups, body, params);
+            cursor
+        }
+        Content::Thread(thread) => {
+            build_thread_html(&thread, body, params);
+            None
+        }
+        Content::Profile(profile, posts) => {
+            build_profile_html(&profile, &posts, body, params);
+            None
+        }
+        Content::Actors(actors, cursor) => {
+            build_actors_html(&actors, &HashSet::new(), body, params);
+            cursor
+        }
+        Content::Members { members, cursor, new, .. } => {
+            build_actors_html(&members, &new, body, params);
+            cursor
+        }
+        Content::AvatarStrip(actors, cursor, reaction) => {
+            build_avatar_strip_html(&actors, reaction.label(), cursor.is_some(), body);
+            cursor
+        }
+    };
+    if params.debug {
+        if let Some(cursor) = cursor {
+            body.push_str(&format!("<p><strong>next cursor:</strong> {}</p>", encode_safe(&cursor)));
         }
-        body.push_str("</ul>");
     }
 }
 
@@ -362,14 +291,31 @@ async fn main() -> std::io::Result<()> {
     dotenv().ok();
     println!("Loaded environment");
 
-    let initial_token = load_tokens();
-    let bsky_state = BskyState {
-        token: Arc::new(Mutex::new(initial_token)),
-    };
-
+    actix_web::rt::spawn(secrets::reload_on_sighup());
+    let accounts = Accounts::from_env();
     println!("Loaded Bluesky state");
-    HttpServer::new(move || App::new().app_data(web::Data::new(bsky_state.clone())).service(index))
-        .bind(("0.0.0.0", 8080))?
-        .run()
-        .await
+
+    for state in accounts.iter() {
+        match AuthMode::for_account(&state.account) {
+            AuthMode::Public => println!("Account {} has no credentials, serving public data only", state.account.name),
+            _ => {
+                // Before the server starts, so a sign-in code prompt never holds up a widget
+                sign_in_at_startup(state).await;
+                actix_web::rt::spawn(run_token_refresher(state.clone()));
+                println!("Started background token refresher for account {}", state.account.name);
+            }
+        }
+    }
+
+    HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(accounts.clone()))
+            .service(index)
+            .service(oauth::login)
+            .service(oauth::callback)
+            .service(oauth::client_metadata)
+    })
+    .bind(("0.0.0.0", 8080))?
+    .run()
+    .await
 }
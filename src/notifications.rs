@@ -0,0 +1,330 @@
+use chrono::{SecondsFormat, Utc};
+use html_escape::{encode_double_quoted_attribute, encode_safe};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+
+use crate::aturi::post_web_link;
+use crate::auth::{xrpc_get, xrpc_post, BskyState};
+use crate::paging::{collect_pages, PageRequest, MAX_TOTAL_ITEMS};
+use crate::params::Params;
+use crate::post::{BskyAuthor, BskyPost};
+use crate::render::format_relative_time;
+
+/// Largest page `listNotifications` will return.
+const NOTIFICATIONS_PAGE_SIZE: usize = 100;
+/// How many raw notifications to read per group the widget shows; likes and reposts collapse a lot.
+const NOTIFICATIONS_PER_GROUP: usize = 5;
+/// Most posts `getPosts` accepts in one call.
+const GET_POSTS_MAX: usize = 25;
+
+/// `reasons` values accepted by `app.bsky.notification.listNotifications`.
+pub const NOTIFICATION_REASONS: [&str; 6] = ["like", "repost", "follow", "mention", "reply", "quote"];
+
+/// One entry of "app.bsky.notification.listNotifications".
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+pub struct BskyNotification {
+    pub uri: String,
+    pub author: BskyAuthor,
+    /// like, repost, follow, mention, reply, quote, ...
+    pub reason: String,
+    /// The post that was liked or reposted.
+    #[serde(rename = "reasonSubject")]
+    pub reason_subject: Option<String>,
+    #[serde(default)]
+    record: Value,
+    #[serde(rename = "isRead", default)]
+    pub is_read: bool,
+    #[serde(rename = "indexedAt")]
+    pub indexed_at: String,
+
+    #[serde(flatten)]
+    extra: HashMap<String, Value>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+struct BskyListNotificationsResponse {
+    #[serde(default)]
+    notifications: Vec<BskyNotification>,
+    #[serde(default)]
+    cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BskyGetPostsResponse {
+    #[serde(default)]
+    posts: Vec<BskyPost>,
+}
+
+/// Notifications with the same reason about the same post, e.g. everyone who liked it.
+#[derive(Debug)]
+pub struct NotificationGroup {
+    pub reason: String,
+    /// The post the group is about: the liked/reposted post, or the reply/mention/quote itself.
+    pub subject: Option<String>,
+    /// Most recent first.
+    pub authors: Vec<BskyAuthor>,
+    /// Text of the reply/mention/quote, or of the liked/reposted post once fetched.
+    pub text: Option<String>,
+    pub indexed_at: String,
+    pub unread: bool,
+}
+
+impl NotificationGroup {
+    fn key(notification: &BskyNotification) -> (String, Option<String>) {
+        match notification.reason.as_str() {
+            // All new followers collapse into one entry
+            "follow" => ("follow".to_string(), None),
+            "like" | "repost" => (notification.reason.clone(), notification.reason_subject.clone()),
+            // Replies, mentions and quotes are each their own post
+            _ => (notification.reason.clone(), Some(notification.uri.clone())),
+        }
+    }
+}
+
+/// Groups notifications (newest first) by reason and post, keeping the order of each group's newest entry.
+fn group_notifications(notifications: Vec<BskyNotification>) -> Vec<NotificationGroup> {
+    let mut groups: Vec<NotificationGroup> = Vec::new();
+    let mut index: HashMap<(String, Option<String>), usize> = HashMap::new();
+    for notification in notifications {
+        let key = NotificationGroup::key(&notification);
+        if let Some(&i) = index.get(&key) {
+            let group = &mut groups[i];
+            group.unread |= !notification.is_read;
+            if !group.authors.iter().any(|a| a.did == notification.author.did) {
+                group.authors.push(notification.author);
+            }
+            continue;
+        }
+        let text = match notification.reason.as_str() {
+            "reply" | "mention" | "quote" => notification.record["text"].as_str().map(str::to_string),
+            _ => None,
+        };
+        index.insert(key.clone(), groups.len());
+        groups.push(NotificationGroup {
+            reason: notification.reason,
+            subject: key.1,
+            authors: vec![notification.author],
+            text,
+            indexed_at: notification.indexed_at,
+            unread: !notification.is_read,
+        });
+    }
+    groups
+}
+
+/// Reads up to `wanted` raw notifications starting at `start`, plus the cursor that follows them.
+async fn list_notifications(
+    client: &Client,
+    state: &BskyState,
+    token: &str,
+    reasons: &[String],
+    start: Option<String>,
+    wanted: usize,
+) -> Result<(Vec<BskyNotification>, Option<String>), Box<dyn std::error::Error>> {
+    let query: Vec<(&str, String)> = reasons.iter().map(|r| ("reasons", r.clone())).collect();
+    let request = PageRequest {
+        method: "app.bsky.notification.listNotifications",
+        token: Some(token),
+        query: &query,
+        page_size: NOTIFICATIONS_PAGE_SIZE,
+    };
+    let extract = |result: BskyListNotificationsResponse| (result.notifications, result.cursor);
+    collect_pages(client, state, &request, start, wanted, extract, |_| true).await
+}
+
+/// How many leading notifications (newest first) belong to the first `groups` groups; the first
+/// notification of the next group, and everything after it, won't be shown.
+fn shown_notifications(notifications: &[BskyNotification], groups: usize) -> usize {
+    let mut keys = HashSet::new();
+    notifications
+        .iter()
+        .position(|notification| keys.insert(NotificationGroup::key(notification)) && keys.len() > groups)
+        .unwrap_or(notifications.len())
+}
+
+/// Fetches the signed-in account's notifications grouped for display, plus the cursor to continue from.
+/// With `mark-seen`, tells Bluesky they've been seen once they're fetched.
+pub async fn fetch_notifications(
+    client: &Client,
+    state: &BskyState,
+    token: Option<&str>,
+    params: &Params,
+) -> Result<(Vec<NotificationGroup>, Option<String>), Box<dyn std::error::Error>> {
+    let Some(token) = token else {
+        return Err("notifications need a signed-in account".into());
+    };
+    let seen_at = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+
+    let wanted = (params.limit * NOTIFICATIONS_PER_GROUP).min(MAX_TOTAL_ITEMS);
+    let (mut notifications, mut cursor) = list_notifications(client, state, token, &params.reasons, params.cursor.clone(), wanted).await?;
+
+    // Notifications past the last shown group would be skipped by the cursor, so when some are
+    // dropped, ask again for just the shown ones to get the cursor that follows them.
+    let shown = shown_notifications(&notifications, params.limit);
+    if shown < notifications.len() {
+        notifications.truncate(shown);
+        (_, cursor) = list_notifications(client, state, token, &params.reasons, params.cursor.clone(), shown).await?;
+    }
+
+    let mut groups = group_notifications(notifications);
+    if let Err(e) = fill_subject_text(client, state, token, &mut groups).await {
+        println!("Could not fetch liked/reposted posts: {}", e);
+    }
+
+    if params.mark_seen {
+        match xrpc_post(
+            client,
+            state,
+            "app.bsky.notification.updateSeen",
+            token,
+            &json!({ "seenAt": seen_at }),
+        )
+        .await
+        {
+            Ok(response) if !response.status().is_success() => println!("Could not mark notifications seen: {}", response.status()),
+            Err(e) => println!("Could not mark notifications seen: {}", e),
+            Ok(_) => {}
+        }
+    }
+    Ok((groups, cursor))
+}
+
+/// Looks up the text of liked and reposted posts so entries can quote them.
+async fn fill_subject_text(
+    client: &Client,
+    state: &BskyState,
+    token: &str,
+    groups: &mut [NotificationGroup],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut uris: Vec<String> = groups
+        .iter()
+        .filter(|g| g.text.is_none() && matches!(g.reason.as_str(), "like" | "repost"))
+        .filter_map(|g| g.subject.clone())
+        .collect();
+    uris.sort();
+    uris.dedup();
+    let mut texts = HashMap::new();
+    for chunk in uris.chunks(GET_POSTS_MAX) {
+        let query: Vec<(&str, &str)> = chunk.iter().map(|uri| ("uris", uri.as_str())).collect();
+        let response = xrpc_get(client, state, "app.bsky.feed.getPosts", Some(token), &query).await?;
+        if !response.status().is_success() {
+            return Err(Box::new(response.error_for_status().unwrap_err()));
+        }
+        let result: BskyGetPostsResponse = serde_json::from_str(&response.text().await?)?;
+        texts.extend(result.posts.into_iter().filter_map(|p| Some((p.uri, p.record.text?))));
+    }
+    for group in groups.iter_mut() {
+        if let Some(text) = group.subject.as_ref().and_then(|uri| texts.get(uri)) {
+            group.text = Some(text.clone());
+        }
+    }
+    Ok(())
+}
+
+/// "alice", "alice and bob", or "alice and 4 others".
+fn describe_authors(authors: &[BskyAuthor]) -> String {
+    let link = |author: &BskyAuthor| {
+        let handle = author.handle.as_deref().unwrap_or_default();
+        format!(
+            r#"<a href="https://bsky.app/profile/{}" target="_blank">{}</a>"#,
+            encode_double_quoted_attribute(handle),
+            encode_safe(author.name())
+        )
+    };
+    match authors {
+        [] => "Someone".to_string(),
+        [one] => link(one),
+        [one, two] => format!("{} and {}", link(one), link(two)),
+        [one, rest @ ..] => format!("{} and {} others", link(one), rest.len()),
+    }
+}
+
+/// Renders grouped notifications, newest first, with unread entries marked.
+pub fn build_notifications_html(groups: &[NotificationGroup], body: &mut String, params: &Params) {
+    if groups.is_empty() {
+        body.push_str("<p>No notifications.</p>");
+        return;
+    }
+    body.push_str(&format!(
+        r#"<ul class="list collapsible-container" data-collapse-after="{}">"#,
+        params.collapse_after
+    ));
+    for group in groups {
+        let who = describe_authors(&group.authors);
+        let action = match group.reason.as_str() {
+            "like" => "liked your post",
+            "repost" => "reposted your post",
+            "follow" => "followed you",
+            "mention" => "mentioned you",
+            "reply" => "replied to you",
+            "quote" => "quoted your post",
+            other => other,
+        };
+        let class = if group.unread {
+            "post-container notification-unread"
+        } else {
+            "post-container"
+        };
+        body.push_str(&format!(
+            r#"<li class="{}"><p class="post-text">{} {}</p>"#,
+            class,
+            who,
+            encode_safe(action)
+        ));
+
+        if let Some(text) = &group.text {
+            let link = group.subject.as_deref().and_then(post_web_link).unwrap_or_default();
+            body.push_str(&format!(
+                r#"<p class="post-text"><a href="{}" target="_blank">{}</a></p>"#,
+                encode_double_quoted_attribute(&link),
+                encode_safe(text)
+            ));
+        }
+        if !params.hide_datetime {
+            body.push_str(&format!(
+                r#"<p class="post-author">{}</p>"#,
+                format_relative_time(&group.indexed_at)
+            ));
+        }
+        body.push_str("</li>");
+    }
+    body.push_str("</ul>");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post::fixtures::TestPost;
+
+    fn notification(reason: &str, subject: &str, author: &str) -> BskyNotification {
+        TestPost::new(subject)
+            .with("uri", format!("at://{}/app.bsky.feed.like/{}", author, subject))
+            .author(author)
+            .with("reason", reason)
+            .with("reasonSubject", subject)
+            .build()
+    }
+
+    #[test]
+    fn shown_notifications_stop_at_the_first_dropped_group() {
+        let notifications = vec![
+            notification("like", "post-a", "alice"),
+            notification("like", "post-b", "bob"),
+            notification("like", "post-a", "carol"),
+            notification("repost", "post-a", "dave"),
+            notification("like", "post-b", "erin"),
+        ];
+        assert_eq!(shown_notifications(&notifications, 1), 1);
+        assert_eq!(shown_notifications(&notifications, 2), 3);
+        assert_eq!(shown_notifications(&notifications, 3), 5);
+        assert_eq!(shown_notifications(&[], 3), 0);
+
+        let groups = group_notifications(notifications.into_iter().take(3).collect());
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].authors.len(), 2);
+    }
+}
@@ -0,0 +1,840 @@
+use actix_web::{get, http::header, http::StatusCode as HttpStatus, web, HttpResponse, Responder};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, TimeZone, Utc};
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use p256::SecretKey;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use reqwest::{Client, Method, Request, Response, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::account::{AccountConfig, Accounts};
+use crate::auth::{save_tokens, AuthMode, BskySession, BskyState};
+use crate::did::resolve_pds_endpoint;
+use crate::secrets::secret;
+
+const OAUTH_SCOPE: &str = "atproto transition:generic";
+const DEFAULT_PUBLIC_URL: &str = "http://127.0.0.1:8080";
+/// How long a sign-in may take between `/oauth/login` and its callback.
+const PENDING_AUTHORIZATION_TTL: Duration = Duration::from_secs(600);
+/// Most sign-ins one account may have in flight. `/oauth/login` needs no authentication, so this
+/// bounds both the pending map and the pushed authorization requests visitors can make us send.
+const MAX_PENDING_AUTHORIZATIONS: usize = 16;
+
+/// Everything needed to use and refresh a DPoP-bound OAuth session.
+/// Stored on `BskySession` so it is persisted alongside the tokens.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OAuthBinding {
+    pub issuer: String,
+    #[serde(rename = "tokenEndpoint")]
+    pub token_endpoint: String,
+    #[serde(rename = "clientId")]
+    pub client_id: String,
+    /// Private DPoP key as a JWK; every proof for this session must be signed with it.
+    #[serde(rename = "dpopKey")]
+    pub dpop_key: String,
+    /// Access token expiry as a unix timestamp, since OAuth access tokens are opaque to us.
+    #[serde(rename = "expiresAt")]
+    pub expires_at: i64,
+    /// When the access token was issued, as a unix timestamp; missing in sessions saved by older versions.
+    #[serde(rename = "issuedAt", default, skip_serializing_if = "Option::is_none")]
+    pub issued_at: Option<i64>,
+    #[serde(rename = "authServerNonce", default)]
+    pub auth_server_nonce: Option<String>,
+    #[serde(rename = "resourceNonce", default)]
+    pub resource_nonce: Option<String>,
+}
+
+impl OAuthBinding {
+    pub fn expiry(&self) -> Option<DateTime<Utc>> {
+        Utc.timestamp_opt(self.expires_at, 0).single()
+    }
+
+    pub fn issued(&self) -> Option<DateTime<Utc>> {
+        Utc.timestamp_opt(self.issued_at?, 0).single()
+    }
+}
+
+/// An authorization request waiting for its `/oauth/callback`, keyed by `state`.
+#[derive(Debug, Clone)]
+pub struct PendingAuthorization {
+    issuer: String,
+    token_endpoint: String,
+    code_verifier: String,
+    dpop_key: String,
+    auth_server_nonce: Option<String>,
+    /// The configured account's DID; the callback refuses tokens for anyone else.
+    expected_did: String,
+    pds: String,
+    started: Instant,
+}
+
+impl PendingAuthorization {
+    fn expired(&self) -> bool {
+        self.started.elapsed() > PENDING_AUTHORIZATION_TTL
+    }
+}
+
+/// OAuth failures, split so callers can tell a refused grant from a transport problem.
+#[derive(Debug)]
+pub enum OAuthError {
+    /// The server answered with an error status.
+    Server { status: StatusCode, body: String },
+    /// Network, decoding or local key problems.
+    Other(String),
+}
+
+impl std::fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OAuthError::Server { status, body } => write!(f, "{} {}", status, body),
+            OAuthError::Other(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for OAuthError {}
+
+impl From<reqwest::Error> for OAuthError {
+    fn from(err: reqwest::Error) -> Self {
+        OAuthError::Other(err.to_string())
+    }
+}
+
+/// The externally reachable base URL of this server, from `BLUESKY_OAUTH_PUBLIC_URL`.
+fn public_url() -> String {
+    secret("BLUESKY_OAUTH_PUBLIC_URL")
+        .unwrap_or_else(|| DEFAULT_PUBLIC_URL.to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+fn is_loopback(url: &str) -> bool {
+    Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h == "localhost" || h == "127.0.0.1" || h == "[::1]"))
+        .unwrap_or(false)
+}
+
+fn redirect_uri() -> String {
+    let public = public_url();
+    if is_loopback(&public) {
+        // Loopback clients must redirect to an IP literal rather than "localhost"
+        let port = Url::parse(&public)
+            .ok()
+            .and_then(|u| u.port())
+            .map(|p| format!(":{}", p))
+            .unwrap_or_default();
+        format!("http://127.0.0.1{}/oauth/callback", port)
+    } else {
+        format!("{}/oauth/callback", public)
+    }
+}
+
+/// Loopback deployments use the special `http://localhost` client id; everything else
+/// points the authorization server at our hosted client metadata document.
+fn client_id() -> String {
+    let public = public_url();
+    if is_loopback(&public) {
+        // The client id must be exactly `http://localhost`, so only borrow Url for query encoding
+        let mut url = Url::parse("http://localhost").expect("static URL parses");
+        url.query_pairs_mut()
+            .append_pair("redirect_uri", &redirect_uri())
+            .append_pair("scope", OAUTH_SCOPE);
+        format!("http://localhost?{}", url.query().unwrap_or_default())
+    } else {
+        format!("{}/oauth/client-metadata.json", public)
+    }
+}
+
+fn random_token(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn sha256_b64(input: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(input.as_bytes()))
+}
+
+/// PKCE `S256` challenge for `code_verifier`.
+fn code_challenge(code_verifier: &str) -> String {
+    sha256_b64(code_verifier)
+}
+
+fn new_dpop_key() -> String {
+    SecretKey::random(&mut OsRng).to_jwk_string().to_string()
+}
+
+fn signing_key(jwk: &str) -> Result<SigningKey, OAuthError> {
+    let secret = SecretKey::from_jwk_str(jwk).map_err(|e| OAuthError::Other(format!("invalid DPoP key: {}", e)))?;
+    Ok(SigningKey::from(secret))
+}
+
+/// Builds a DPoP proof JWT for one request. `access_token` adds the `ath` claim for resource requests.
+fn dpop_proof(key_jwk: &str, method: &Method, url: &str, nonce: Option<&str>, access_token: Option<&str>) -> Result<String, OAuthError> {
+    let key = signing_key(key_jwk)?;
+    let public_jwk = key.verifying_key().to_encoded_point(false);
+    let jwk = json!({
+        "kty": "EC",
+        "crv": "P-256",
+        "x": URL_SAFE_NO_PAD.encode(public_jwk.x().ok_or_else(|| OAuthError::Other("bad DPoP key".to_string()))?),
+        "y": URL_SAFE_NO_PAD.encode(public_jwk.y().ok_or_else(|| OAuthError::Other("bad DPoP key".to_string()))?),
+    });
+
+    // htu is the request URL without query or fragment
+    let mut htu = Url::parse(url).map_err(|e| OAuthError::Other(e.to_string()))?;
+    htu.set_query(None);
+    htu.set_fragment(None);
+
+    let header = json!({ "typ": "dpop+jwt", "alg": "ES256", "jwk": jwk });
+    let mut claims = json!({
+        "jti": random_token(16),
+        "htm": method.as_str(),
+        "htu": htu.as_str(),
+        "iat": Utc::now().timestamp(),
+    });
+    if let Some(nonce) = nonce {
+        claims["nonce"] = json!(nonce);
+    }
+    if let Some(token) = access_token {
+        claims["ath"] = json!(sha256_b64(token));
+    }
+
+    let signing_input = format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(header.to_string()),
+        URL_SAFE_NO_PAD.encode(claims.to_string())
+    );
+    let signature: Signature = key.sign(signing_input.as_bytes());
+    Ok(format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(signature.to_bytes())))
+}
+
+fn response_nonce(response: &Response) -> Option<String> {
+    response
+        .headers()
+        .get("DPoP-Nonce")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+/// POSTs a form to the authorization server with a DPoP proof, retrying once when the
+/// server hands us a fresh nonce. `nonce` is updated with whatever the server sent last.
+async fn post_with_dpop<T: DeserializeOwned>(
+    client: &Client,
+    key_jwk: &str,
+    url: &str,
+    form: &[(&str, &str)],
+    nonce: &mut Option<String>,
+) -> Result<T, OAuthError> {
+    for attempt in 0..2 {
+        let proof = dpop_proof(key_jwk, &Method::POST, url, nonce.as_deref(), None)?;
+        let response = client.post(url).header("DPoP", proof).form(form).send().await?;
+        if let Some(new_nonce) = response_nonce(&response) {
+            *nonce = Some(new_nonce);
+        }
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response.json().await?);
+        }
+        let body = response.text().await.unwrap_or_default();
+        if attempt == 0 && body.contains("use_dpop_nonce") {
+            continue;
+        }
+        return Err(OAuthError::Server { status, body });
+    }
+    unreachable!("the second attempt always returns")
+}
+
+/// Sends a DPoP-bound request to the PDS/AppView, picking up and retrying with a new nonce once if asked.
+/// `request` is the bare XRPC request; the authorization and proof headers are added here.
+pub async fn dpop_request(
+    client: &Client,
+    state: &BskyState,
+    binding: &OAuthBinding,
+    token: &str,
+    request: Request,
+) -> Result<Response, OAuthError> {
+    let mut nonce = binding.resource_nonce.clone();
+    for attempt in 0..2 {
+        let mut attempt_request = request
+            .try_clone()
+            .ok_or_else(|| OAuthError::Other("request body can't be resent".to_string()))?;
+        let proof = dpop_proof(
+            &binding.dpop_key,
+            attempt_request.method(),
+            attempt_request.url().as_str(),
+            nonce.as_deref(),
+            Some(token),
+        )?;
+        let headers = attempt_request.headers_mut();
+        headers.insert(
+            header::AUTHORIZATION.as_str(),
+            format!("DPoP {}", token)
+                .parse()
+                .map_err(|_| OAuthError::Other("invalid access token".to_string()))?,
+        );
+        headers.insert(
+            "DPoP",
+            proof.parse().map_err(|_| OAuthError::Other("invalid DPoP proof".to_string()))?,
+        );
+        let response = client.execute(attempt_request).await?;
+
+        let new_nonce = response_nonce(&response);
+        if new_nonce.is_some() && new_nonce != nonce {
+            nonce = new_nonce;
+            remember_resource_nonce(state, nonce.clone()).await;
+            let wants_nonce = response
+                .headers()
+                .get(header::WWW_AUTHENTICATE.as_str())
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| v.contains("use_dpop_nonce"));
+            if attempt == 0 && response.status() == StatusCode::UNAUTHORIZED && wants_nonce {
+                continue;
+            }
+        }
+        return Ok(response);
+    }
+    unreachable!("the second attempt always returns")
+}
+
+async fn remember_resource_nonce(state: &BskyState, nonce: Option<String>) {
+    if let Some(binding) = state.token.lock().await.as_mut().and_then(|s| s.oauth.as_mut()) {
+        binding.resource_nonce = nonce;
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: String,
+    #[serde(default)]
+    token_type: String,
+    #[serde(default)]
+    expires_in: Option<i64>,
+    sub: String,
+}
+
+fn expires_at(tokens: &TokenResponse) -> i64 {
+    // Fall back to a conservative lifetime when the server doesn't say
+    Utc::now().timestamp() + tokens.expires_in.unwrap_or(300)
+}
+
+/// Exchanges the refresh token for a new DPoP-bound access token.
+pub async fn refresh_oauth_session(
+    account: &AccountConfig,
+    current: &BskySession,
+    binding: &OAuthBinding,
+) -> Result<BskySession, OAuthError> {
+    let client = Client::new();
+    let mut nonce = binding.auth_server_nonce.clone();
+    let form = [
+        ("grant_type", "refresh_token"),
+        ("refresh_token", current.refresh_jwt.as_str()),
+        ("client_id", binding.client_id.as_str()),
+    ];
+    let tokens: TokenResponse = post_with_dpop(&client, &binding.dpop_key, &binding.token_endpoint, &form, &mut nonce).await?;
+    if tokens.sub != current.did {
+        return Err(OAuthError::Other(format!(
+            "token refresh returned a different account: {}",
+            tokens.sub
+        )));
+    }
+
+    let session = BskySession {
+        access_jwt: tokens.access_token.clone(),
+        refresh_jwt: tokens.refresh_token.clone(),
+        did: tokens.sub.clone(),
+        pds_endpoint: current.pds_endpoint.clone(),
+        oauth: Some(OAuthBinding {
+            expires_at: expires_at(&tokens),
+            issued_at: Some(Utc::now().timestamp()),
+            auth_server_nonce: nonce,
+            ..binding.clone()
+        }),
+    };
+    save_tokens(account, &session);
+    println!("OAuth token refreshed successfully.");
+    Ok(session)
+}
+
+#[derive(Deserialize)]
+struct ProtectedResourceMetadata {
+    authorization_servers: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct AuthServerMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    pushed_authorization_request_endpoint: String,
+}
+
+async fn fetch_json<T: DeserializeOwned>(client: &Client, url: &str) -> Result<T, OAuthError> {
+    Ok(client.get(url).send().await?.error_for_status()?.json().await?)
+}
+
+/// Finds the authorization server for `pds`, unless the account's `BLUESKY_OAUTH_ISSUER` pins one
+/// (handy for pointing at a local mock authorization server).
+async fn discover_auth_server(client: &Client, account: &AccountConfig, pds: &str) -> Result<AuthServerMetadata, OAuthError> {
+    let issuer = match account.setting("OAUTH_ISSUER") {
+        Some(issuer) => issuer,
+        None => {
+            let url = format!("{}/.well-known/oauth-protected-resource", pds.trim_end_matches('/'));
+            let resource: ProtectedResourceMetadata = fetch_json(client, &url).await?;
+            resource
+                .authorization_servers
+                .into_iter()
+                .next()
+                .ok_or_else(|| OAuthError::Other(format!("{} lists no authorization servers", pds)))?
+        }
+    };
+    let issuer = issuer.trim_end_matches('/').to_string();
+    let url = format!("{}/.well-known/oauth-authorization-server", issuer);
+    let metadata: AuthServerMetadata = fetch_json(client, &url).await?;
+    if metadata.issuer.trim_end_matches('/') != issuer {
+        return Err(OAuthError::Other(format!(
+            "issuer mismatch: expected {}, got {}",
+            issuer, metadata.issuer
+        )));
+    }
+    Ok(metadata)
+}
+
+/// Resolves a handle (or DID) to its DID and PDS so we ask the right authorization server.
+async fn resolve_account(client: &Client, state: &BskyState, handle: &str) -> Result<(String, String), OAuthError> {
+    #[derive(Deserialize)]
+    struct ResolveHandleResponse {
+        did: String,
+    }
+
+    let did = if handle.starts_with("did:") {
+        handle.to_string()
+    } else {
+        let url = state.endpoints.lock().await.pds_xrpc("com.atproto.identity.resolveHandle");
+        let resolved: ResolveHandleResponse = client
+            .get(url)
+            .query(&[("handle", handle)])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        resolved.did
+    };
+    let pds = resolve_pds_endpoint(client, &state.account, &did)
+        .await
+        .map_err(|e| OAuthError::Other(e.to_string()))?;
+    Ok((did, pds))
+}
+
+/// Starts signing in as `identifier`, the account's configured handle or DID.
+async fn start_authorization(client: &Client, state: &BskyState, identifier: &str) -> Result<String, OAuthError> {
+    {
+        let mut pending = state.oauth_pending.lock().await;
+        pending.retain(|_, p| !p.expired());
+        if pending.len() >= MAX_PENDING_AUTHORIZATIONS {
+            return Err(OAuthError::Other(
+                "too many sign-ins in progress; finish one or try again in a few minutes".to_string(),
+            ));
+        }
+    }
+    let (expected_did, pds) = resolve_account(client, state, identifier).await?;
+    let metadata = discover_auth_server(client, &state.account, &pds).await?;
+
+    let code_verifier = random_token(32);
+    let oauth_state = random_token(16);
+    let dpop_key = new_dpop_key();
+    let mut nonce = None;
+    let client_id = client_id();
+    let redirect_uri = redirect_uri();
+    let code_challenge = code_challenge(&code_verifier);
+
+    let form = [
+        ("client_id", client_id.as_str()),
+        ("response_type", "code"),
+        ("code_challenge", code_challenge.as_str()),
+        ("code_challenge_method", "S256"),
+        ("state", oauth_state.as_str()),
+        ("redirect_uri", redirect_uri.as_str()),
+        ("scope", OAUTH_SCOPE),
+        ("login_hint", identifier),
+    ];
+
+    #[derive(Deserialize)]
+    struct ParResponse {
+        request_uri: String,
+    }
+    let par: ParResponse = post_with_dpop(
+        client,
+        &dpop_key,
+        &metadata.pushed_authorization_request_endpoint,
+        &form,
+        &mut nonce,
+    )
+    .await?;
+
+    state.oauth_pending.lock().await.insert(
+        oauth_state,
+        PendingAuthorization {
+            issuer: metadata.issuer.trim_end_matches('/').to_string(),
+            token_endpoint: metadata.token_endpoint,
+            code_verifier,
+            dpop_key,
+            auth_server_nonce: nonce,
+            expected_did,
+            pds,
+            started: Instant::now(),
+        },
+    );
+
+    let mut authorize = Url::parse(&metadata.authorization_endpoint).map_err(|e| OAuthError::Other(e.to_string()))?;
+    authorize
+        .query_pairs_mut()
+        .append_pair("client_id", &client_id)
+        .append_pair("request_uri", &par.request_uri);
+    Ok(authorize.to_string())
+}
+
+async fn finish_authorization(client: &Client, state: &BskyState, query: &HashMap<String, String>) -> Result<BskySession, OAuthError> {
+    // Every callback uses up its state, including denied and failed ones
+    let oauth_state = query.get("state").ok_or_else(|| OAuthError::Other("missing state".to_string()))?;
+    let pending = state
+        .oauth_pending
+        .lock()
+        .await
+        .remove(oauth_state)
+        .filter(|p| !p.expired())
+        .ok_or_else(|| OAuthError::Other("unknown, expired or already used state".to_string()))?;
+    if let Some(error) = query.get("error") {
+        let description = query.get("error_description").cloned().unwrap_or_default();
+        return Err(OAuthError::Other(format!("authorization failed: {} {}", error, description)));
+    }
+    let code = query.get("code").ok_or_else(|| OAuthError::Other("missing code".to_string()))?;
+    if let Some(iss) = query.get("iss") {
+        if iss.trim_end_matches('/') != pending.issuer {
+            return Err(OAuthError::Other(format!(
+                "issuer mismatch: expected {}, got {}",
+                pending.issuer, iss
+            )));
+        }
+    }
+
+    let client_id = client_id();
+    let redirect_uri = redirect_uri();
+    let form = [
+        ("grant_type", "authorization_code"),
+        ("code", code.as_str()),
+        ("redirect_uri", redirect_uri.as_str()),
+        ("client_id", client_id.as_str()),
+        ("code_verifier", pending.code_verifier.as_str()),
+    ];
+    let mut nonce = pending.auth_server_nonce.clone();
+    let tokens: TokenResponse = post_with_dpop(client, &pending.dpop_key, &pending.token_endpoint, &form, &mut nonce).await?;
+
+    if !tokens.token_type.eq_ignore_ascii_case("DPoP") {
+        return Err(OAuthError::Other(format!("expected a DPoP token, got {:?}", tokens.token_type)));
+    }
+    // Only the configured account may replace the server's session
+    if tokens.sub != pending.expected_did {
+        return Err(OAuthError::Other(format!(
+            "signed in as {}, but this server is configured for {}",
+            tokens.sub, pending.expected_did
+        )));
+    }
+
+    let session = BskySession {
+        access_jwt: tokens.access_token.clone(),
+        refresh_jwt: tokens.refresh_token.clone(),
+        did: tokens.sub.clone(),
+        pds_endpoint: Some(pending.pds),
+        oauth: Some(OAuthBinding {
+            issuer: pending.issuer,
+            token_endpoint: pending.token_endpoint,
+            client_id,
+            dpop_key: pending.dpop_key,
+            expires_at: expires_at(&tokens),
+            issued_at: Some(Utc::now().timestamp()),
+            auth_server_nonce: nonce,
+            resource_nonce: None,
+        }),
+    };
+    save_tokens(&state.account, &session);
+    Ok(session)
+}
+
+fn oauth_page(status: HttpStatus, message: &str) -> HttpResponse {
+    HttpResponse::build(status).insert_header(header::ContentType::html()).body(format!(
+        "<!DOCTYPE html><html><body><p>{}</p></body></html>",
+        html_escape::encode_safe(message)
+    ))
+}
+
+/// Starts the OAuth flow for `?account=` (default account otherwise), always as the account's
+/// configured `USERNAME`: anyone can reach this page, so it must not pick whose session we store.
+#[get("/oauth/login")]
+async fn login(query: web::Query<HashMap<String, String>>, accounts: web::Data<Accounts>) -> impl Responder {
+    let Some(data) = accounts.get(query.get("account").map(String::as_str)) else {
+        return oauth_page(HttpStatus::NOT_FOUND, "Unknown account.");
+    };
+    if AuthMode::for_account(&data.account) != AuthMode::OAuth {
+        return oauth_page(
+            HttpStatus::NOT_FOUND,
+            &format!("OAuth is disabled. Set {}=oauth to enable it.", data.account.var_name("AUTH_MODE")),
+        );
+    }
+    let Some(identifier) = data.account.var("USERNAME") else {
+        return oauth_page(
+            HttpStatus::INTERNAL_SERVER_ERROR,
+            &format!(
+                "Set {} to the handle or DID of the account to sign in with.",
+                data.account.var_name("USERNAME")
+            ),
+        );
+    };
+    match start_authorization(&Client::new(), data, &identifier).await {
+        Ok(url) => HttpResponse::Found().insert_header((header::LOCATION, url)).finish(),
+        Err(e) => oauth_page(HttpStatus::BAD_GATEWAY, &format!("Could not start Bluesky sign-in: {}", e)),
+    }
+}
+
+#[get("/oauth/callback")]
+async fn callback(query: web::Query<HashMap<String, String>>, accounts: web::Data<Accounts>) -> impl Responder {
+    // The account is whichever one started the authorization request carrying this state
+    let oauth_state = query.get("state").cloned().unwrap_or_default();
+    let mut owner = None;
+    for data in accounts.iter() {
+        if data.oauth_pending.lock().await.contains_key(&oauth_state) {
+            owner = Some(data);
+            break;
+        }
+    }
+    let Some(data) = owner else {
+        return oauth_page(
+            HttpStatus::BAD_REQUEST,
+            "Bluesky sign-in failed: unknown, expired or already used state",
+        );
+    };
+
+    match finish_authorization(&Client::new(), data, &query).await {
+        Ok(session) => {
+            let did = session.did.clone();
+            data.endpoints.lock().await.adopt_session(&session);
+            *data.token.lock().await = Some(session);
+            println!("Signed in with OAuth as {} for account {}.", did, data.account.name);
+            oauth_page(HttpStatus::OK, &format!("Signed in to Bluesky as {}. You can close this tab.", did))
+        }
+        Err(e) => oauth_page(HttpStatus::BAD_REQUEST, &format!("Bluesky sign-in failed: {}", e)),
+    }
+}
+
+/// Client metadata document, served at the URL we use as our `client_id` outside loopback setups.
+#[get("/oauth/client-metadata.json")]
+async fn client_metadata() -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "client_id": client_id(),
+        "client_name": "Glance Bluesky widget",
+        "client_uri": public_url(),
+        "redirect_uris": [redirect_uri()],
+        "grant_types": ["authorization_code", "refresh_token"],
+        "response_types": ["code"],
+        "scope": OAUTH_SCOPE,
+        "token_endpoint_auth_method": "none",
+        "application_type": "web",
+        "dpop_bound_access_tokens": true,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::ServiceEndpoints;
+    use actix_web::{App, HttpRequest, HttpServer};
+    use p256::ecdsa::signature::Verifier;
+    use p256::ecdsa::VerifyingKey;
+    use p256::{EncodedPoint, FieldBytes};
+    use serde_json::Value;
+    use std::sync::{Arc, Mutex};
+
+    const MOCK_NONCE: &str = "server-nonce-1";
+
+    /// Decodes a proof's header and claims, checking its signature against the key in its header.
+    fn decode_proof(proof: &str) -> (Value, Value) {
+        let parts: Vec<&str> = proof.split('.').collect();
+        assert_eq!(parts.len(), 3, "a JWT has three parts");
+        let decode = |part: &str| serde_json::from_slice::<Value>(&URL_SAFE_NO_PAD.decode(part).unwrap()).unwrap();
+        let (header, claims) = (decode(parts[0]), decode(parts[1]));
+
+        let coordinate = |name: &str| URL_SAFE_NO_PAD.decode(header["jwk"][name].as_str().unwrap()).unwrap();
+        let (x, y) = (coordinate("x"), coordinate("y"));
+        let point = EncodedPoint::from_affine_coordinates(FieldBytes::from_slice(&x), FieldBytes::from_slice(&y), false);
+        let key = VerifyingKey::from_encoded_point(&point).unwrap();
+        let signature = Signature::from_slice(&URL_SAFE_NO_PAD.decode(parts[2]).unwrap()).unwrap();
+        key.verify(format!("{}.{}", parts[0], parts[1]).as_bytes(), &signature)
+            .expect("proof is signed by the key in its header");
+        (header, claims)
+    }
+
+    #[test]
+    fn dpop_proof_carries_request_claims() {
+        let key = new_dpop_key();
+        let proof = dpop_proof(
+            &key,
+            &Method::GET,
+            "https://pds.example/xrpc/app.bsky.feed.getTimeline?limit=5#top",
+            Some("abc"),
+            Some("access-token"),
+        )
+        .unwrap();
+        let (header, claims) = decode_proof(&proof);
+        assert_eq!(header["typ"], "dpop+jwt");
+        assert_eq!(header["alg"], "ES256");
+        assert!(header["jwk"].get("d").is_none(), "the private key must not leak into the proof");
+        assert_eq!(claims["htm"], "GET");
+        assert_eq!(claims["htu"], "https://pds.example/xrpc/app.bsky.feed.getTimeline");
+        assert_eq!(claims["nonce"], "abc");
+        assert_eq!(claims["ath"], sha256_b64("access-token"));
+        assert!(claims["jti"].as_str().is_some_and(|jti| !jti.is_empty()));
+        assert!(claims["iat"].is_i64());
+    }
+
+    #[test]
+    fn dpop_proof_omits_nonce_and_ath_when_not_given() {
+        let proof = dpop_proof(&new_dpop_key(), &Method::POST, "https://auth.example/token", None, None).unwrap();
+        let (_, claims) = decode_proof(&proof);
+        assert_eq!(claims["htm"], "POST");
+        assert!(claims.get("nonce").is_none());
+        assert!(claims.get("ath").is_none());
+    }
+
+    #[test]
+    fn code_challenge_is_s256() {
+        // RFC 7636, appendix B
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    /// The proofs each mock endpoint received, in order.
+    type Proofs = web::Data<Mutex<Vec<String>>>;
+
+    fn received_proof(request: &HttpRequest, proofs: &Proofs) -> Value {
+        let proof = request.headers().get("DPoP").and_then(|v| v.to_str().ok()).unwrap_or_default();
+        proofs.lock().unwrap().push(proof.to_string());
+        decode_proof(proof).1
+    }
+
+    /// An authorization server token endpoint that insists on `MOCK_NONCE`.
+    async fn mock_token(request: HttpRequest, proofs: Proofs) -> HttpResponse {
+        if received_proof(&request, &proofs)["nonce"] == MOCK_NONCE {
+            return HttpResponse::Ok().json(json!({ "ok": true }));
+        }
+        HttpResponse::BadRequest()
+            .insert_header(("DPoP-Nonce", MOCK_NONCE))
+            .json(json!({ "error": "use_dpop_nonce" }))
+    }
+
+    /// A resource server endpoint that insists on `MOCK_NONCE`, answering the way a PDS does.
+    async fn mock_resource(request: HttpRequest, proofs: Proofs) -> HttpResponse {
+        let authorized = request.headers().get("Authorization").and_then(|v| v.to_str().ok()) == Some("DPoP access-token");
+        if received_proof(&request, &proofs)["nonce"] == MOCK_NONCE && authorized {
+            return HttpResponse::Ok().json(json!({ "feed": [] }));
+        }
+        HttpResponse::Unauthorized()
+            .insert_header(("DPoP-Nonce", MOCK_NONCE))
+            .insert_header(("WWW-Authenticate", r#"DPoP error="use_dpop_nonce""#))
+            .json(json!({ "error": "use_dpop_nonce" }))
+    }
+
+    /// Starts the mock servers on a free local port and returns their base URL.
+    fn start_mock_server(proofs: Proofs) -> String {
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(proofs.clone())
+                .route("/token", web::post().to(mock_token))
+                .route("/xrpc/app.bsky.feed.getTimeline", web::get().to(mock_resource))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let base = format!("http://{}", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        base
+    }
+
+    #[actix_web::test]
+    async fn post_with_dpop_retries_with_the_servers_nonce() {
+        let proofs: Proofs = web::Data::new(Mutex::new(Vec::new()));
+        let base = start_mock_server(proofs.clone());
+        let url = format!("{}/token", base);
+
+        let mut nonce = None;
+        let response: Value = post_with_dpop(
+            &Client::new(),
+            &new_dpop_key(),
+            &url,
+            &[("grant_type", "refresh_token")],
+            &mut nonce,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response["ok"], true);
+        assert_eq!(nonce.as_deref(), Some(MOCK_NONCE), "the new nonce is handed back for next time");
+
+        let proofs = proofs.lock().unwrap();
+        assert_eq!(proofs.len(), 2);
+        assert!(decode_proof(&proofs[0]).1.get("nonce").is_none());
+        let (_, retry) = decode_proof(&proofs[1]);
+        assert_eq!(retry["nonce"], MOCK_NONCE);
+        assert_eq!(retry["htm"], "POST");
+        assert_eq!(retry["htu"], url);
+    }
+
+    #[actix_web::test]
+    async fn dpop_request_retries_with_the_servers_nonce() {
+        let proofs: Proofs = web::Data::new(Mutex::new(Vec::new()));
+        let base = start_mock_server(proofs.clone());
+        let account = AccountConfig::default_account();
+        let state = BskyState {
+            token: Arc::new(tokio::sync::Mutex::new(None)),
+            endpoints: Arc::new(tokio::sync::Mutex::new(ServiceEndpoints::for_account(&account))),
+            oauth_pending: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+            account,
+        };
+        let binding = OAuthBinding {
+            issuer: base.clone(),
+            token_endpoint: format!("{}/token", base),
+            client_id: "http://localhost".to_string(),
+            dpop_key: new_dpop_key(),
+            expires_at: 0,
+            issued_at: None,
+            auth_server_nonce: None,
+            resource_nonce: None,
+        };
+
+        let client = Client::new();
+        let url = format!("{}/xrpc/app.bsky.feed.getTimeline", base);
+        let request = client.get(&url).query(&[("limit", "5")]).build().unwrap();
+        let response = dpop_request(&client, &state, &binding, "access-token", request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let proofs = proofs.lock().unwrap();
+        assert_eq!(proofs.len(), 2);
+        let (_, retry) = decode_proof(&proofs[1]);
+        assert_eq!(retry["nonce"], MOCK_NONCE);
+        assert_eq!(retry["htm"], "GET");
+        assert_eq!(retry["htu"], url, "htu leaves out the query");
+        assert_eq!(retry["ath"], sha256_b64("access-token"));
+    }
+}
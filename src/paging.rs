@@ -0,0 +1,94 @@
+use reqwest::Client;
+use serde::de::DeserializeOwned;
+
+use crate::auth::{xrpc_get, BskyState};
+use crate::params::Params;
+
+/// Most items any widget will collect across pages, however large `limit` is.
+pub const MAX_TOTAL_ITEMS: usize = 250;
+/// Most requests one widget render will make while following cursors.
+pub const MAX_PAGES: usize = 10;
+
+/// A cursor-paginated XRPC query: the method, who's asking, the parameters every page repeats, and
+/// the largest `limit` the endpoint accepts.
+pub struct PageRequest<'a> {
+    pub method: &'a str,
+    pub token: Option<&'a str>,
+    pub query: &'a [(&'a str, String)],
+    pub page_size: usize,
+}
+
+/// Follows the cursor of `request` from `start` until `wanted` items are collected, the source runs
+/// dry, or `MAX_PAGES` requests have been made. Asking for more than `MAX_TOTAL_ITEMS` is an error.
+/// `extract` pulls one page of items and the next cursor out of a response; a page without items
+/// ends paging whatever its cursor says. Only items `keep` accepts count towards `wanted`, so pages
+/// may come back empty after filtering without ending the search.
+/// Returns the collected items and the cursor to continue from, if any.
+pub async fn collect_pages<R: DeserializeOwned, T>(
+    client: &Client,
+    state: &BskyState,
+    request: &PageRequest<'_>,
+    start: Option<String>,
+    wanted: usize,
+    extract: impl Fn(R) -> (Vec<T>, Option<String>),
+    keep: impl Fn(&T) -> bool,
+) -> Result<(Vec<T>, Option<String>), Box<dyn std::error::Error>> {
+    if wanted > MAX_TOTAL_ITEMS {
+        return Err(format!("can't collect {} items, at most {} are fetched per widget", wanted, MAX_TOTAL_ITEMS).into());
+    }
+    let mut items = Vec::new();
+    let mut cursor = start;
+
+    for _ in 0..MAX_PAGES {
+        if items.len() >= wanted {
+            break;
+        }
+        let mut query = request.query.to_vec();
+        query.push(("limit", (wanted - items.len()).min(request.page_size).to_string()));
+        if let Some(cursor) = cursor.take() {
+            query.push(("cursor", cursor));
+        }
+
+        let response = xrpc_get(client, state, request.method, request.token, &query).await?;
+        if !response.status().is_success() {
+            return Err(Box::new(response.error_for_status().unwrap_err()));
+        }
+        let (page, next) = extract(serde_json::from_str(&response.text().await?)?);
+        if page.is_empty() {
+            break;
+        }
+        items.extend(page.into_iter().filter(|item| keep(item)));
+        cursor = next;
+        if cursor.is_none() {
+            break;
+        }
+    }
+
+    items.truncate(wanted);
+    Ok((items, cursor))
+}
+
+/// How many items come before the widget's page. Continuing from `cursor` picks up exactly where
+/// the previous page left off; otherwise earlier pages are fetched and skipped.
+pub fn page_offset(params: &Params, cursor: Option<&str>) -> usize {
+    if cursor.is_some() {
+        0
+    } else {
+        (params.page - 1) * params.limit
+    }
+}
+
+/// Collects the page of `request` the widget asked for with its `page`, `cursor` and `limit`
+/// parameters. `extract` and `keep` work as in `collect_pages`.
+pub async fn collect_page<R: DeserializeOwned, T>(
+    client: &Client,
+    state: &BskyState,
+    request: &PageRequest<'_>,
+    params: &Params,
+    extract: impl Fn(R) -> (Vec<T>, Option<String>),
+    keep: impl Fn(&T) -> bool,
+) -> Result<(Vec<T>, Option<String>), Box<dyn std::error::Error>> {
+    let skip = page_offset(params, params.cursor.as_deref());
+    let (items, cursor) = collect_pages(client, state, request, params.cursor.clone(), skip + params.limit, extract, keep).await?;
+    Ok((items.into_iter().skip(skip).collect(), cursor))
+}
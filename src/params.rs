@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+
+use crate::aturi::RecordRef;
+use crate::engagement::Reaction;
+use crate::feed::{AUTHOR_FEED_FILTERS, FEED_PAGE_SIZE};
+use crate::graph::FollowDirection;
+use crate::notifications::NOTIFICATION_REASONS;
+use crate::paging::MAX_TOTAL_ITEMS;
+use crate::tags::TagExpr;
+use crate::thread::{MAX_THREAD_DEPTH, THREAD_SORTS};
+use crate::timespec::parse_time_spec;
+
+/// `app.bsky.embed.images` allows at most four images per post.
+const MAX_EMBED_IMAGES: usize = 4;
+
+/// Where a widget's posts come from.
+pub enum Source {
+    /// `app.bsky.feed.searchPosts`, driven by `tags`, `q` and the search filters.
+    Search,
+    /// One account's posts and reposts (`actor=`).
+    AuthorFeed { actor: String, filter: Option<String> },
+    /// A custom feed generator (`feed=`).
+    CustomFeed(RecordRef),
+    /// Posts from a list's members (`list=`).
+    ListFeed(RecordRef),
+    /// The signed-in account's home timeline (`source=timeline`).
+    Timeline,
+    /// The signed-in account's notifications, grouped (`source=notifications`).
+    Notifications,
+    /// A post and its replies as a tree (`thread=`).
+    Thread(RecordRef),
+    /// An account's profile card (`source=profile&actor=`).
+    Profile(String),
+    /// Accounts matching `q` (`source=actors`).
+    Actors { q: String, typeahead: bool },
+    /// Posts quoting a post (`quotes=`).
+    Quotes(RecordRef),
+    /// Accounts that liked (`likes=`) or reposted (`reposts=`) a post.
+    Reactors(RecordRef, Reaction),
+    /// An account's followers (`followers=`) or the accounts it follows (`follows=`).
+    FollowList(String, FollowDirection),
+    /// The members of a starter pack (`starter-pack=`).
+    StarterPack(RecordRef),
+}
+
+fn parse_source(query: &HashMap<String, String>, errors: &mut Vec<String>) -> Source {
+    let param = |name: &str| query.get(name).map(|s| s.trim().to_string()).filter(|s| !s.is_empty());
+
+    match param("source").as_deref() {
+        None | Some("search") => {}
+        Some("timeline") => return Source::Timeline,
+        Some("notifications") => return Source::Notifications,
+        Some("profile") => match param("actor") {
+            Some(actor) => return Source::Profile(actor),
+            None => errors.push("source=profile needs an actor".to_string()),
+        },
+        Some("actors") => match param("q") {
+            Some(q) => {
+                let typeahead = param("typeahead").and_then(|s| s.parse::<bool>().ok()).unwrap_or(false);
+                return Source::Actors { q, typeahead };
+            }
+            None => errors.push("source=actors needs a q to search for".to_string()),
+        },
+        Some(other) => errors.push(format!(
+            "Invalid source \"{}\": use search, timeline, notifications, profile or actors",
+            other
+        )),
+    }
+
+    if let Some(actor) = param("actor") {
+        let filter = param("filter");
+        if let Some(filter) = &filter {
+            if !AUTHOR_FEED_FILTERS.contains(&filter.as_str()) {
+                errors.push(format!(
+                    "Invalid filter \"{}\": use one of {}",
+                    filter,
+                    AUTHOR_FEED_FILTERS.join(", ")
+                ));
+            }
+        }
+        return Source::AuthorFeed { actor, filter };
+    }
+    if let Some(feed) = param("feed") {
+        match RecordRef::parse(&feed, "app.bsky.feed.generator") {
+            Ok(feed) => return Source::CustomFeed(feed),
+            Err(e) => errors.push(format!("Invalid feed {}", e)),
+        }
+    }
+    if let Some(thread) = param("thread") {
+        let sort = param("sort").unwrap_or_default();
+        if !sort.is_empty() && !THREAD_SORTS.contains(&sort.as_str()) {
+            errors.push(format!("Invalid sort \"{}\" for a thread: use {}", sort, THREAD_SORTS.join(", ")));
+        }
+        match RecordRef::parse(&thread, "app.bsky.feed.post") {
+            Ok(post) => return Source::Thread(post),
+            Err(e) => errors.push(format!("Invalid thread {}", e)),
+        }
+    }
+    if let Some(post) = param("quotes") {
+        match RecordRef::parse(&post, "app.bsky.feed.post") {
+            Ok(post) => return Source::Quotes(post),
+            Err(e) => errors.push(format!("Invalid quotes {}", e)),
+        }
+    }
+    for (name, reaction) in [("likes", Reaction::Likes), ("reposts", Reaction::Reposts)] {
+        if let Some(post) = param(name) {
+            match RecordRef::parse(&post, "app.bsky.feed.post") {
+                Ok(post) => return Source::Reactors(post, reaction),
+                Err(e) => errors.push(format!("Invalid {} {}", name, e)),
+            }
+        }
+    }
+    for (name, direction) in [("followers", FollowDirection::Followers), ("follows", FollowDirection::Follows)] {
+        if let Some(actor) = param(name) {
+            return Source::FollowList(actor, direction);
+        }
+    }
+    if let Some(pack) = param("starter-pack") {
+        match RecordRef::parse(&pack, "app.bsky.graph.starterpack") {
+            Ok(pack) => return Source::StarterPack(pack),
+            Err(e) => errors.push(format!("Invalid starter-pack {}", e)),
+        }
+    }
+    if let Some(list) = param("list") {
+        match RecordRef::parse(&list, "app.bsky.graph.list") {
+            Ok(list) => return Source::ListFeed(list),
+            Err(e) => errors.push(format!("Invalid list {}", e)),
+        }
+    }
+    Source::Search
+}
+
+pub struct Params {
+    pub tags: TagExpr,
+    pub limit: usize,
+    pub debug: bool,
+    pub text_color: String,
+    pub author_color: String,
+    pub text_hover_color: String,
+    pub author_hover_color: String,
+    pub text_visited_color: String,
+    pub maybe_since_time: Option<DateTime<Utc>>,
+    pub maybe_until_time: Option<DateTime<Utc>>,
+    /// Parameter values that couldn't be parsed, shown instead of fetching anything.
+    pub errors: Vec<String>,
+    pub source: Source,
+    pub text: Option<String>,
+    pub author: Option<String>,
+    pub mentions: Option<String>,
+    pub domain: Option<String>,
+    pub url: Option<String>,
+    pub lang: Option<String>,
+    pub sort: String,
+    /// The `title` parameter; sources may supply their own default.
+    pub title: Option<String>,
+    pub collapse_after: usize,
+    pub hide_stats: bool,
+    pub hide_datetime: bool,
+    pub hide_author: bool,
+    pub show_images: bool,
+    /// Most thumbnails shown per post (posts carry at most four).
+    pub max_images: usize,
+    /// Thumbnail edge length, in pixels.
+    pub thumbnail_size: u32,
+    pub hide_reposts: bool,
+    pub hide_replies: bool,
+    pub hide_unfollowed_replies: bool,
+    /// Notification reasons to show; empty means all.
+    pub reasons: Vec<String>,
+    pub mark_seen: bool,
+    /// How many reply levels a thread shows.
+    pub depth: usize,
+    /// How many recent posts a profile card shows underneath.
+    pub profile_posts: usize,
+    pub account: Option<String>,
+    pub page: usize,
+    pub cursor: Option<String>,
+}
+
+impl Params {
+    pub fn widget_title(&self) -> &str {
+        self.title.as_deref().unwrap_or("Bluesky")
+    }
+}
+
+pub fn parse_params(query: &HashMap<String, String>) -> Params {
+    let tags_param = query.get("tags").cloned().unwrap_or_default();
+    let limit = query
+        .get("limit")
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(10)
+        .clamp(1, MAX_TOTAL_ITEMS);
+    let debug = query.get("debug").and_then(|s| s.parse::<bool>().ok()).unwrap_or(false);
+    let text_color = query.get("text-color").cloned().unwrap_or("000000".to_string());
+    let author_color = query.get("author-color").cloned().unwrap_or("666".to_string());
+    let text_hover_color = query.get("text-hover-color").cloned().unwrap_or("000000".to_string());
+    let author_hover_color = query.get("author-hover-color").cloned().unwrap_or("666".to_string());
+    let text_visited_color = query.get("text-visited-color").cloned().unwrap_or("666".to_string());
+    let now = Utc::now();
+    let mut errors = Vec::new();
+    let mut time_param = |name: &str| match query.get(name).filter(|s| !s.trim().is_empty()) {
+        Some(spec) => parse_time_spec(spec, now)
+            .map_err(|e| errors.push(format!("Invalid {} {}", name, e)))
+            .ok(),
+        None => None,
+    };
+    let maybe_since_time = time_param("since");
+    let maybe_until_time = time_param("until");
+    if let (Some(since), Some(until)) = (maybe_since_time, maybe_until_time) {
+        if since >= until {
+            errors.push("Invalid time range: since must be earlier than until".to_string());
+        }
+    }
+
+    let source = parse_source(query, &mut errors);
+
+    let text = query.get("q").cloned();
+    let author = query.get("author").cloned();
+    let mentions = query.get("mentions").cloned();
+    let domain = query.get("domain").cloned();
+    let url = query.get("url").cloned();
+    let lang = query.get("lang").cloned();
+
+    let sort = query.get("sort").cloned().unwrap_or("latest".to_string());
+    let title = query.get("title").cloned().filter(|s| !s.is_empty());
+    let collapse_after = query.get("collapse-after").and_then(|s| s.parse::<usize>().ok()).unwrap_or(5);
+    let hide_stats = query.get("hide-stats").and_then(|s| s.parse::<bool>().ok()).unwrap_or(false);
+    let hide_datetime = query.get("hide-datetime").and_then(|s| s.parse::<bool>().ok()).unwrap_or(false);
+    let hide_author = query.get("hide-author").and_then(|s| s.parse::<bool>().ok()).unwrap_or(false);
+    let show_images = query.get("show-images").and_then(|s| s.parse::<bool>().ok()).unwrap_or(true);
+    let max_images = query
+        .get("max-images")
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(MAX_EMBED_IMAGES)
+        .min(MAX_EMBED_IMAGES);
+    let thumbnail_size = query
+        .get("thumbnail-size")
+        .and_then(|s| s.parse::<u32>().ok())
+        .unwrap_or(96)
+        .clamp(32, 512);
+    let hide_reposts = query.get("hide-reposts").and_then(|s| s.parse::<bool>().ok()).unwrap_or(false);
+    let hide_replies = query.get("hide-replies").and_then(|s| s.parse::<bool>().ok()).unwrap_or(false);
+    let hide_unfollowed_replies = query
+        .get("hide-unfollowed-replies")
+        .and_then(|s| s.parse::<bool>().ok())
+        .unwrap_or(false);
+    let reasons: Vec<String> = query
+        .get("reasons")
+        .map(|s| s.split(',').map(str::trim).filter(|r| !r.is_empty()).map(str::to_string).collect())
+        .unwrap_or_default();
+    for reason in reasons.iter().filter(|r| !NOTIFICATION_REASONS.contains(&r.as_str())) {
+        errors.push(format!("Invalid reason \"{}\": use {}", reason, NOTIFICATION_REASONS.join(", ")));
+    }
+    let mark_seen = query.get("mark-seen").and_then(|s| s.parse::<bool>().ok()).unwrap_or(false);
+    let depth = query
+        .get("depth")
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(3)
+        .clamp(1, MAX_THREAD_DEPTH);
+    let profile_posts = query
+        .get("posts")
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(0)
+        .min(FEED_PAGE_SIZE);
+    let account = query.get("account").cloned();
+    let page = query.get("page").and_then(|s| s.parse::<usize>().ok()).unwrap_or(1).max(1);
+    let cursor = query.get("cursor").cloned().filter(|s| !s.is_empty());
+    // Earlier pages are fetched and skipped, so only the first MAX_TOTAL_ITEMS items are reachable by page
+    if cursor.is_none() && page.saturating_mul(limit) > MAX_TOTAL_ITEMS {
+        errors.push(format!(
+            "Invalid page {}: with limit {} the last reachable page is {} (the first {} items); use cursor to go further",
+            page,
+            limit,
+            MAX_TOTAL_ITEMS / limit,
+            MAX_TOTAL_ITEMS
+        ));
+    }
+    // Grouping merges a varying number of notifications into each entry, so there's no fixed offset to skip to
+    if page > 1 && matches!(source, Source::Notifications) {
+        errors.push(format!(
+            "Invalid page {}: notifications can only be paged with cursor (shown with debug: true)",
+            page
+        ));
+    }
+
+    let tags = TagExpr::parse(&tags_param).unwrap_or_else(|e| {
+        errors.push(format!("Invalid tags {}", e));
+        TagExpr::default()
+    });
+
+    Params {
+        tags,
+        limit,
+        debug,
+        text_color,
+        author_color,
+        text_hover_color,
+        author_hover_color,
+        text_visited_color,
+        maybe_since_time,
+        maybe_until_time,
+        errors,
+        source,
+        text,
+        author,
+        mentions,
+        domain,
+        url,
+        lang,
+        sort,
+        title,
+        collapse_after,
+        hide_stats,
+        hide_datetime,
+        hide_author,
+        show_images,
+        max_images,
+        thumbnail_size,
+        hide_reposts,
+        hide_replies,
+        hide_unfollowed_replies,
+        reasons,
+        mark_seen,
+        depth,
+        profile_posts,
+        account,
+        page,
+        cursor,
+    }
+}
@@ -1,4 +1,4 @@
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use serde_json::Value;
 use std::collections::HashMap;
 
@@ -27,8 +27,14 @@ pub struct BskyPost {
     viewer: Value,
     #[serde(default)]
     labels: Value,
-    #[serde(default)]
-    embed: Value,
+    /// The hydrated embed, when it's one we render. Unknown or malformed embeds are dropped
+    /// rather than failing the whole post.
+    #[serde(default, deserialize_with = "lenient")]
+    pub embed: Option<BskyEmbedView>,
+
+    /// Set when this post came from a feed item that is someone's repost of it.
+    #[serde(skip)]
+    pub reposted_by: Option<BskyAuthor>,
 
     /// Flatten any fields we didn’t explicitly define so we don’t lose them.
     /// This makes debugging easier if new fields appear in the JSON.
@@ -36,15 +42,73 @@ pub struct BskyPost {
     extra: HashMap<String, Value>,
 }
 
+/// A post's embed as the AppView returns it, with CDN URLs filled in.
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+#[serde(tag = "$type")]
+pub enum BskyEmbedView {
+    #[serde(rename = "app.bsky.embed.images#view")]
+    Images(BskyImagesView),
+    /// A quote post with media attached; the media is what we show.
+    #[serde(rename = "app.bsky.embed.recordWithMedia#view")]
+    RecordWithMedia { media: Box<BskyEmbedView> },
+    /// External links, videos, plain quotes and anything newer.
+    #[serde(other)]
+    Other,
+}
+
+/// "app.bsky.embed.images#view": up to four images.
+#[derive(Debug, Deserialize)]
+pub struct BskyImagesView {
+    #[serde(default)]
+    pub images: Vec<BskyViewImage>,
+}
+
+/// One image of "app.bsky.embed.images#view".
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+pub struct BskyViewImage {
+    /// Small, cropped version for feeds.
+    pub thumb: String,
+    /// Full-size version for viewing on its own.
+    pub fullsize: String,
+    /// Alt text; empty when the author didn't add any.
+    #[serde(default)]
+    pub alt: String,
+    #[serde(rename = "aspectRatio")]
+    aspect_ratio: Option<Value>,
+}
+
+impl BskyEmbedView {
+    /// The images to show for this embed, if any.
+    pub fn images(&self) -> &[BskyViewImage] {
+        match self {
+            BskyEmbedView::Images(view) => &view.images,
+            BskyEmbedView::RecordWithMedia { media } => media.images(),
+            BskyEmbedView::Other => &[],
+        }
+    }
+}
+
+/// Deserializes an optional field, treating anything unparseable as absent.
+fn lenient<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: serde::de::DeserializeOwned,
+{
+    let value = Value::deserialize(deserializer)?;
+    Ok(serde_json::from_value(value).ok())
+}
+
 /// The “author” sub-object (e.g., who posted it).
 #[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 pub struct BskyAuthor {
-    did: Option<String>,
+    pub did: Option<String>,
     pub handle: Option<String>,
     #[serde(rename = "displayName")]
-    display_name: Option<String>,
-    avatar: Option<String>,
+    pub display_name: Option<String>,
+    pub avatar: Option<String>,
 
     #[serde(default)]
     associated: Value,
@@ -91,7 +155,7 @@ pub struct BskySearchPostsResponse {
 
     /// For pagination, if present
     #[serde(default)]
-    cursor: Option<String>,
+    pub cursor: Option<String>,
 
     #[serde(default)]
     sort: Option<String>,
@@ -100,3 +164,323 @@ pub struct BskySearchPostsResponse {
     #[serde(flatten)]
     extra: HashMap<String, Value>,
 }
+
+/// One item of a feed ("app.bsky.feed.defs#feedViewPost"): a post, plus why it's in the feed.
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+pub struct BskyFeedViewPost {
+    pub post: BskyPost,
+    /// Present when the item is a repost or a pinned post.
+    #[serde(default)]
+    pub reason: Option<BskyFeedReason>,
+    /// Present when the post is a reply.
+    #[serde(default)]
+    pub reply: Option<BskyReplyRef>,
+
+    #[serde(flatten)]
+    extra: HashMap<String, Value>,
+}
+
+/// The posts a feed item replies to. Each is a post view, or a notFound/blocked stub.
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+pub struct BskyReplyRef {
+    #[serde(default)]
+    root: Value,
+    #[serde(default)]
+    parent: Value,
+
+    #[serde(flatten)]
+    extra: HashMap<String, Value>,
+}
+
+/// Why a post appears in a feed, e.g. "app.bsky.feed.defs#reasonRepost".
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+pub struct BskyFeedReason {
+    #[serde(rename = "$type")]
+    pub reason_type: Option<String>,
+    /// Who reposted it, for reposts.
+    pub by: Option<BskyAuthor>,
+    #[serde(rename = "indexedAt")]
+    indexed_at: Option<String>,
+
+    #[serde(flatten)]
+    extra: HashMap<String, Value>,
+}
+
+/// The response shared by the feed endpoints ("getAuthorFeed", "getFeed", "getTimeline", ...)
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+pub struct BskyFeedResponse {
+    #[serde(default)]
+    pub feed: Vec<BskyFeedViewPost>,
+
+    #[serde(default)]
+    pub cursor: Option<String>,
+
+    #[serde(flatten)]
+    extra: HashMap<String, Value>,
+}
+
+/// A list ("app.bsky.graph.defs#listView"), as returned by "getList".
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+pub struct BskyListView {
+    pub uri: String,
+    pub name: String,
+    #[serde(default)]
+    purpose: Option<String>,
+    #[serde(default)]
+    description: Option<String>,
+
+    #[serde(flatten)]
+    extra: HashMap<String, Value>,
+}
+
+/// The "getList" response; we only ask for the list itself, not its members.
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+pub struct BskyGetListResponse {
+    pub list: BskyListView,
+
+    #[serde(flatten)]
+    extra: HashMap<String, Value>,
+}
+
+/// A node of a "getPostThread" tree. Replies can be hidden from us, so not every node is a post.
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+#[serde(tag = "$type")]
+pub enum BskyThreadNode {
+    #[serde(rename = "app.bsky.feed.defs#threadViewPost")]
+    Post(Box<BskyThreadViewPost>),
+    #[serde(rename = "app.bsky.feed.defs#notFoundPost")]
+    NotFound { uri: String },
+    #[serde(rename = "app.bsky.feed.defs#blockedPost")]
+    Blocked { uri: String },
+    /// Any node type added after this was written.
+    #[serde(other)]
+    Unknown,
+}
+
+/// A post in a thread, with the replies fetched down to the requested depth.
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+pub struct BskyThreadViewPost {
+    pub post: BskyPost,
+    #[serde(default)]
+    pub replies: Vec<BskyThreadNode>,
+    #[serde(default)]
+    parent: Value,
+
+    #[serde(flatten)]
+    extra: HashMap<String, Value>,
+}
+
+/// The "getPostThread" response.
+#[derive(Debug, Deserialize)]
+pub struct BskyGetPostThreadResponse {
+    pub thread: BskyThreadNode,
+}
+
+/// A full profile ("app.bsky.actor.defs#profileViewDetailed"), as returned by "getProfile".
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+pub struct BskyProfile {
+    pub did: String,
+    pub handle: String,
+    #[serde(rename = "displayName")]
+    pub display_name: Option<String>,
+    pub description: Option<String>,
+    pub avatar: Option<String>,
+    pub banner: Option<String>,
+    #[serde(rename = "followersCount")]
+    pub followers_count: Option<u64>,
+    #[serde(rename = "followsCount")]
+    pub follows_count: Option<u64>,
+    #[serde(rename = "postsCount")]
+    pub posts_count: Option<u64>,
+
+    #[serde(default)]
+    viewer: Value,
+    #[serde(default)]
+    labels: Value,
+
+    #[serde(flatten)]
+    extra: HashMap<String, Value>,
+}
+
+impl BskyFeedViewPost {
+    pub fn is_repost(&self) -> bool {
+        self.reason.as_ref().and_then(|r| r.reason_type.as_deref()) == Some("app.bsky.feed.defs#reasonRepost")
+    }
+
+    pub fn is_reply(&self) -> bool {
+        self.reply.is_some() || !self.post.record.reply.is_null()
+    }
+
+    /// Whether the thread this reply belongs to is between people `viewer_did` follows (or themselves).
+    /// Relies on the `viewer.following` state the AppView only fills in for signed-in requests.
+    pub fn replies_within_follows(&self, viewer_did: &str) -> bool {
+        let Some(reply) = &self.reply else {
+            return !self.is_reply();
+        };
+        [&reply.parent, &reply.root].into_iter().all(|post| {
+            let author = &post["author"];
+            author["did"].as_str() == Some(viewer_did) || author["viewer"]["following"].is_string()
+        })
+    }
+
+    /// The post, carrying repost attribution along when the item is a repost.
+    pub fn into_post(self) -> BskyPost {
+        let repost = self.is_repost();
+        let mut post = self.post;
+        if repost {
+            post.reposted_by = self.reason.and_then(|r| r.by);
+        }
+        post
+    }
+}
+
+/// Label values that mark a post's media as adult or graphic.
+const ADULT_MEDIA_LABELS: [&str; 4] = ["porn", "sexual", "nudity", "graphic-media"];
+
+impl BskyPost {
+    /// Whether moderation labels, or the author's self-labels on the record, mark the post's media
+    /// as adult or graphic. Negated labels don't count.
+    pub fn has_adult_media(&self) -> bool {
+        let moderation = self
+            .labels
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|label| !label.get("neg").and_then(Value::as_bool).unwrap_or(false))
+            .filter_map(|label| label.get("val")?.as_str());
+        let self_labels = self
+            .record
+            .extra
+            .get("labels")
+            .and_then(|labels| labels.get("values")?.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|label| label.get("val")?.as_str());
+        moderation.chain(self_labels).any(|val| ADULT_MEDIA_LABELS.contains(&val))
+    }
+}
+
+impl BskyAuthor {
+    /// The display name, falling back to the handle.
+    pub fn name(&self) -> &str {
+        self.display_name
+            .as_deref()
+            .filter(|n| !n.trim().is_empty())
+            .or(self.handle.as_deref())
+            .unwrap_or_default()
+    }
+}
+
+impl BskyPostRecord {
+    /// Hashtags from the record's `app.bsky.richtext.facet#tag` facets and its `tags` field.
+    pub fn hashtags(&self) -> Vec<String> {
+        let facet_tags = self
+            .facets
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|facet| facet.get("features")?.as_array())
+            .flatten()
+            .filter(|feature| feature.get("$type").and_then(Value::as_str) == Some("app.bsky.richtext.facet#tag"))
+            .filter_map(|feature| feature.get("tag")?.as_str());
+        let record_tags = self
+            .extra
+            .get("tags")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str);
+        facet_tags.chain(record_tags).map(str::to_string).collect()
+    }
+}
+
+/// A post view for tests, as the AppView would send it. Notifications share its shape, so their
+/// tests build them from one too.
+#[cfg(test)]
+pub mod fixtures {
+    use serde::de::DeserializeOwned;
+    use serde_json::{json, Value};
+
+    pub struct TestPost(Value);
+
+    impl TestPost {
+        /// An empty post with record key `rkey`, by `did:plc:a`.
+        pub fn new(rkey: &str) -> Self {
+            TestPost(json!({
+                "uri": format!("at://did:plc:a/app.bsky.feed.post/{}", rkey),
+                "indexedAt": "2024-11-05T09:00:00Z",
+                "record": { "text": "" },
+            }))
+        }
+
+        /// Sets a field of the view, e.g. `uri`, `likeCount` or `labels`.
+        pub fn with(mut self, key: &str, value: impl Into<Value>) -> Self {
+            self.0[key] = value.into();
+            self
+        }
+
+        /// Sets a field of the post record, e.g. `text`, `tags` or `facets`.
+        pub fn with_record(mut self, key: &str, value: impl Into<Value>) -> Self {
+            self.0["record"][key] = value.into();
+            self
+        }
+
+        pub fn indexed_at(self, indexed_at: &str) -> Self {
+            self.with("indexedAt", indexed_at)
+        }
+
+        /// Credits the post to `did`, with handle `<did>.test`.
+        pub fn author(self, did: &str) -> Self {
+            self.with("author", json!({ "did": did, "handle": format!("{}.test", did) }))
+        }
+
+        pub fn json(self) -> Value {
+            self.0
+        }
+
+        pub fn build<T: DeserializeOwned>(self) -> T {
+            serde_json::from_value(self.0).unwrap()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::TestPost;
+    use super::*;
+    use serde_json::json;
+
+    fn post(labels: Value, record_labels: Value) -> BskyPost {
+        TestPost::new("1")
+            .with("labels", labels)
+            .with_record("labels", record_labels)
+            .build()
+    }
+
+    #[test]
+    fn adult_media_comes_from_moderation_or_self_labels() {
+        assert!(!post(json!([]), Value::Null).has_adult_media());
+        assert!(post(json!([{ "src": "did:plc:mod", "val": "porn" }]), Value::Null).has_adult_media());
+        assert!(post(
+            json!([]),
+            json!({ "$type": "com.atproto.label.defs#selfLabels", "values": [{ "val": "nudity" }] })
+        )
+        .has_adult_media());
+        assert!(post(json!([{ "val": "graphic-media" }, { "val": "!no-unauthenticated" }]), Value::Null).has_adult_media());
+    }
+
+    #[test]
+    fn other_and_negated_labels_leave_media_shown() {
+        assert!(!post(json!([{ "val": "!no-unauthenticated" }, { "val": "spam" }]), Value::Null).has_adult_media());
+        assert!(!post(json!([{ "val": "sexual", "neg": true }]), Value::Null).has_adult_media());
+    }
+}
@@ -0,0 +1,129 @@
+use html_escape::{encode_double_quoted_attribute, encode_safe};
+use reqwest::Client;
+
+use crate::auth::{xrpc_get, BskyState};
+use crate::feed::fetch_recent_posts;
+use crate::params::Params;
+use crate::post::{BskyPost, BskyProfile};
+use crate::render::build_posts_html;
+
+/// Fetches an account's profile via `app.bsky.actor.getProfile`, plus its latest `posts` posts when asked.
+pub async fn fetch_profile(
+    client: &Client,
+    state: &BskyState,
+    token: Option<&str>,
+    actor: &str,
+    params: &Params,
+) -> Result<(BskyProfile, Vec<BskyPost>), Box<dyn std::error::Error>> {
+    let actor = actor.trim_start_matches('@');
+    let response = xrpc_get(client, state, "app.bsky.actor.getProfile", token, &[("actor", actor)]).await?;
+    if !response.status().is_success() {
+        return Err(Box::new(response.error_for_status().unwrap_err()));
+    }
+    let profile: BskyProfile = serde_json::from_str(&response.text().await?)?;
+
+    let posts = if params.profile_posts > 0 {
+        fetch_recent_posts(client, state, token, &profile.did, params.profile_posts).await?
+    } else {
+        Vec::new()
+    };
+    Ok((profile, posts))
+}
+
+/// Renders the profile header block: banner, avatar, name and handle, bio and counts,
+/// followed by the recent posts if any were fetched.
+pub fn build_profile_html(profile: &BskyProfile, posts: &[BskyPost], body: &mut String, params: &Params) {
+    let profile_link = format!("https://bsky.app/profile/{}", encode_double_quoted_attribute(&profile.handle));
+
+    body.push_str(r#"<div class="profile-card">"#);
+    if let Some(banner) = &profile.banner {
+        body.push_str(&format!(
+            r#"<img class="profile-banner" src="{}" alt="" loading="lazy"/>"#,
+            encode_double_quoted_attribute(banner)
+        ));
+    }
+    body.push_str(r#"<div class="profile-header">"#);
+    if let Some(avatar) = &profile.avatar {
+        body.push_str(&format!(
+            r#"<img class="profile-avatar" src="{}" alt="" loading="lazy"/>"#,
+            encode_double_quoted_attribute(avatar)
+        ));
+    }
+    let name = profile
+        .display_name
+        .as_deref()
+        .filter(|n| !n.trim().is_empty())
+        .unwrap_or(&profile.handle);
+    body.push_str(&format!(
+        r#"<div><p class="post-text"><a href="{}" target="_blank">{}</a></p><p class="post-author">@{}</p></div>"#,
+        profile_link,
+        encode_safe(name),
+        encode_safe(&profile.handle)
+    ));
+    body.push_str("</div>");
+
+    if let Some(description) = profile.description.as_deref().filter(|d| !d.trim().is_empty()) {
+        body.push_str(&format!(r#"<p class="post-text profile-bio">{}</p>"#, linkify(description)));
+    }
+    if !params.hide_stats {
+        body.push_str(&format!(
+            r#"<p class="post-stats">
+               <a href="{link}/followers" target="_blank">Followers: {}</a> &nbsp;&middot;&nbsp;
+               <a href="{link}/follows" target="_blank">Following: {}</a> &nbsp;&middot;&nbsp;
+               Posts: {}
+               </p>"#,
+            profile.followers_count.unwrap_or(0),
+            profile.follows_count.unwrap_or(0),
+            profile.posts_count.unwrap_or(0),
+            link = profile_link
+        ));
+    }
+    body.push_str("</div>");
+
+    if params.profile_posts > 0 {
+        build_posts_html(posts, body, params);
+    }
+}
+
+/// Escapes a bio and links the facets Bluesky would detect in it: URLs, @mentions and #hashtags.
+/// Profile descriptions don't carry facets, so like the app we find them in the text.
+fn linkify(text: &str) -> String {
+    let mut html = String::new();
+    for (i, line) in text.lines().enumerate() {
+        if i > 0 {
+            html.push_str("<br/>");
+        }
+        for (j, word) in line.split(' ').enumerate() {
+            if j > 0 {
+                html.push(' ');
+            }
+            html.push_str(&linkify_word(word));
+        }
+    }
+    html
+}
+
+fn linkify_word(word: &str) -> String {
+    // Trailing punctuation usually ends the sentence, not the link
+    let core = word.trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '"', '\'']);
+    let trailing = &word[core.len()..];
+
+    let href = if core.starts_with("https://") || core.starts_with("http://") {
+        Some(core.to_string())
+    } else if let Some(handle) = core.strip_prefix('@').filter(|h| h.contains('.') && !h.starts_with('.')) {
+        Some(format!("https://bsky.app/profile/{}", handle))
+    } else {
+        core.strip_prefix('#')
+            .filter(|tag| !tag.is_empty() && !tag.chars().all(|c| c.is_ascii_digit()))
+            .map(|tag| format!("https://bsky.app/hashtag/{}", tag))
+    };
+    match href {
+        Some(href) => format!(
+            r#"<a href="{}" target="_blank">{}</a>{}"#,
+            encode_double_quoted_attribute(&href),
+            encode_safe(core),
+            encode_safe(trailing)
+        ),
+        None => encode_safe(word).to_string(),
+    }
+}
@@ -0,0 +1,267 @@
+use chrono::{DateTime, Utc};
+use html_escape::{encode_double_quoted_attribute, encode_safe};
+use humantime::format_duration;
+
+use crate::params::Params;
+use crate::post::BskyPost;
+
+pub fn format_relative_time(datetime: &str) -> String {
+    let parsed_datetime = DateTime::parse_from_rfc3339(datetime).unwrap_or_else(|_| Utc::now().into());
+    let secs = Utc::now().signed_duration_since(parsed_datetime).num_seconds();
+    let duration = std::time::Duration::from_secs(secs.max(0) as u64);
+    format!("{} ago", format_duration(duration))
+}
+
+pub fn build_html_header(params: &Params) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+    <html>
+    <head>
+        <meta charset="utf-8"/>
+        <title>Bluesky Hashtag Viewer</title>
+        <style>
+            .post-container {{
+                margin-bottom: 1em;
+                padding: 0.5em;
+                text-align: left;
+            }}
+            .post-text {{
+                margin: 0;
+                font-size: 1em;
+            }}
+            .post-text a {{
+                color: #{text_color};
+                text-decoration: none;
+            }}
+            .post-text a:hover {{
+                color: #{text_hover_color};
+                text-decoration: none;
+            }}
+            .post-text a:visited {{
+                color: #{text_visited_color};
+                text-decoration: none;
+            }}
+            .post-author {{
+                margin: 0.25em 0 0 0;
+                font-size: 0.85em;
+                color: #{author_color};
+            }}
+            .post-author a {{
+                color: inherit;
+                text-decoration: none;
+            }}
+            .post-author a:hover {{
+                color: #{author_hover_color};
+                text-decoration: none;
+            }}
+            .post-stats {{
+                margin: 0.25em 0 0 0;
+                font-size: 0.85em;
+                color: #{author_color};
+            }}
+            .post-stats a {{
+                color: inherit;
+                text-decoration: none;
+            }}
+            .profile-banner {{
+                width: 100%;
+                max-height: 8em;
+                object-fit: cover;
+                border-radius: 0.25em;
+            }}
+            .profile-header {{
+                display: flex;
+                align-items: center;
+                gap: 0.75em;
+                margin: 0.5em 0;
+            }}
+            .profile-avatar {{
+                width: 3.5em;
+                height: 3.5em;
+                border-radius: 50%;
+                object-fit: cover;
+            }}
+            .actor-avatar {{
+                width: 2.5em;
+                height: 2.5em;
+                flex-shrink: 0;
+                border-radius: 50%;
+                object-fit: cover;
+            }}
+            .avatar-strip {{
+                display: flex;
+                flex-wrap: wrap;
+                gap: 0.25em;
+            }}
+            .strip-avatar {{
+                display: inline-flex;
+                align-items: center;
+                justify-content: center;
+                width: 2em;
+                height: 2em;
+                border-radius: 50%;
+                object-fit: cover;
+                font-size: 0.85em;
+                color: #{author_color};
+                border: 1px solid #{author_color};
+            }}
+            .new-badge {{
+                margin-left: auto;
+                font-size: 0.75em;
+                padding: 0 0.4em;
+                border-radius: 0.5em;
+                color: #{author_hover_color};
+                border: 1px solid #{author_hover_color};
+            }}
+            .profile-bio a {{
+                color: #{author_hover_color};
+            }}
+            .post-images {{
+                display: flex;
+                flex-wrap: wrap;
+                align-items: center;
+                gap: 0.25em;
+                margin: 0.25em 0 0 0;
+            }}
+            .post-image {{
+                width: {thumbnail_size}px;
+                height: {thumbnail_size}px;
+                object-fit: cover;
+                border-radius: 0.25em;
+            }}
+            .thread-replies {{
+                margin-left: 0.75em;
+                padding-left: 0.5em;
+                border-left: 1px solid #{author_color};
+            }}
+            .notification-unread {{
+                border-left: 2px solid #{author_color};
+            }}
+            .post-stats a:hover {{
+                color: #{author_hover_color};
+                text-decoration: none;
+            }}
+        </style>
+    </head>
+    <body>
+    "#,
+        text_color = params.text_color,
+        text_hover_color = params.text_hover_color,
+        text_visited_color = params.text_visited_color,
+        author_color = params.author_color,
+        author_hover_color = params.author_hover_color,
+        thumbnail_size = params.thumbnail_size
+    )
+}
+
+pub fn build_posts_html(posts: &[BskyPost], body: &mut String, params: &Params) {
+    if posts.is_empty() {
+        body.push_str("<p>No posts found.</p>");
+    } else {
+        body.push_str(&format!(
+            r#"<ul class="list collapsible-container" data-collapse-after="{}">"#,
+            params.collapse_after
+        ));
+        for post in posts {
+            body.push_str(r#"<li class="post-container">"#);
+            build_post_html(post, body, params);
+            body.push_str("</li>");
+        }
+        body.push_str("</ul>");
+    }
+}
+
+/// Renders one post's text, author line and stats, without the surrounding list item.
+pub fn build_post_html(post: &BskyPost, body: &mut String, params: &Params) {
+    let post_text = post.record.text.as_deref().unwrap_or("<no text>");
+    let escaped_post_text = encode_safe(&post_text);
+    let author_handle = post.author.as_ref().and_then(|a| a.handle.clone()).unwrap_or_default();
+    let rkey = post.uri.split('/').next_back().unwrap_or("");
+    let post_link = format!(
+        "https://bsky.app/profile/{}/post/{}",
+        encode_double_quoted_attribute(&author_handle),
+        encode_double_quoted_attribute(rkey)
+    );
+    let author_link = format!("https://bsky.app/profile/{}", encode_double_quoted_attribute(&author_handle));
+    let created_at = post.record.created_at.as_deref().unwrap_or("<unknown date>");
+    let relative_time = format_relative_time(created_at);
+    let like_count = post.like_count.unwrap_or(0);
+    let quote_count = post.quote_count.unwrap_or(0);
+    let reply_count = post.reply_count.unwrap_or(0);
+    let repost_count = post.repost_count.unwrap_or(0);
+
+    if let Some(reposter) = &post.reposted_by {
+        let reposter_handle = reposter.handle.as_deref().unwrap_or_default();
+        body.push_str(&format!(
+            r#"<p class="post-author">Reposted by <a href="https://bsky.app/profile/{}" target="_blank">{}</a></p>"#,
+            encode_double_quoted_attribute(reposter_handle),
+            encode_safe(reposter_handle)
+        ));
+    }
+    body.push_str(&format!(
+        r#"<p class="post-text"><a href="{}" target="_blank">{}</a></p>"#,
+        post_link, escaped_post_text
+    ));
+    if params.show_images {
+        build_images_html(post, body, params);
+    }
+
+    if !params.hide_author || !params.hide_datetime {
+        body.push_str(r#"<p class="post-author">"#);
+        if !params.hide_author {
+            body.push_str(&format!(
+                r#"<a href="{}" target="_blank">{}</a>"#,
+                author_link,
+                encode_safe(&author_handle)
+            ));
+        }
+        if !params.hide_author && !params.hide_datetime {
+            body.push_str("&nbsp;&middot;&nbsp;");
+        }
+        if !params.hide_datetime {
+            body.push_str(&format!("{} ({})", created_at, relative_time));
+        }
+        body.push_str("</p>");
+    }
+
+    if !params.hide_stats {
+        body.push_str(&format!(
+            r#"<p class="post-stats">
+               Likes: {} &nbsp;&middot;&nbsp;
+               Quotes: {} &nbsp;&middot;&nbsp;
+               Replies: {} &nbsp;&middot;&nbsp;
+               Reposts: {}
+               </p>"#,
+            like_count, quote_count, reply_count, repost_count
+        ));
+    }
+}
+
+/// Renders up to `max-images` thumbnails from the post's image embed, each linking to the full-size
+/// image, with the alt text in `alt` (and as a tooltip).
+fn build_images_html(post: &BskyPost, body: &mut String, params: &Params) {
+    let images = post.embed.as_ref().map(|embed| embed.images()).unwrap_or_default();
+    if images.is_empty() || params.max_images == 0 {
+        return;
+    }
+    if post.has_adult_media() {
+        body.push_str(r#"<p class="post-stats">Images hidden: labelled as adult or graphic content</p>"#);
+        return;
+    }
+    body.push_str(r#"<div class="post-images">"#);
+    for image in images.iter().take(params.max_images) {
+        let alt = encode_double_quoted_attribute(&image.alt);
+        body.push_str(&format!(
+            r#"<a href="{}" target="_blank"><img class="post-image" src="{}" alt="{}" title="{}" loading="lazy"/></a>"#,
+            encode_double_quoted_attribute(&image.fullsize),
+            encode_double_quoted_attribute(&image.thumb),
+            alt,
+            alt
+        ));
+    }
+    let hidden = images.len().saturating_sub(params.max_images);
+    if hidden > 0 {
+        body.push_str(&format!(r#"<span class="post-stats">+{}</span>"#, hidden));
+    }
+    body.push_str("</div>");
+}
@@ -0,0 +1,336 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures_util::future::try_join_all;
+use reqwest::Client;
+use std::collections::HashSet;
+
+use crate::auth::BskyState;
+use crate::paging::{collect_pages, page_offset, PageRequest};
+use crate::params::Params;
+use crate::post::{BskyPost, BskySearchPostsResponse};
+
+/// Largest page `searchPosts` will return.
+const SEARCH_PAGE_SIZE: usize = 50;
+
+/// A typed `app.bsky.feed.searchPosts` query. Hashtags and free text go into `q`;
+/// everything else maps onto the endpoint's own parameters.
+#[derive(Debug, Default, Clone)]
+pub struct SearchQuery {
+    text: Option<String>,
+    tags: Vec<String>,
+    author: Option<String>,
+    mentions: Option<String>,
+    domain: Option<String>,
+    url: Option<String>,
+    lang: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    sort: Option<String>,
+}
+
+impl SearchQuery {
+    pub fn new() -> Self {
+        SearchQuery::default()
+    }
+
+    /// Free text, passed through as written (so Bluesky's own search syntax works too).
+    pub fn text(mut self, text: Option<&str>) -> Self {
+        self.text = non_empty(text);
+        self
+    }
+
+    /// Hashtags that must all be present, written without the leading `#`.
+    pub fn tags(mut self, tags: &[String]) -> Self {
+        self.tags = tags.to_vec();
+        self
+    }
+
+    /// Only posts by this handle or DID.
+    pub fn author(mut self, author: Option<&str>) -> Self {
+        self.author = non_empty(author).map(|a| a.trim_start_matches('@').to_string());
+        self
+    }
+
+    /// Only posts mentioning this handle or DID.
+    pub fn mentions(mut self, mentions: Option<&str>) -> Self {
+        self.mentions = non_empty(mentions).map(|m| m.trim_start_matches('@').to_string());
+        self
+    }
+
+    /// Only posts linking to this domain, e.g. `github.com`.
+    pub fn domain(mut self, domain: Option<&str>) -> Self {
+        self.domain = non_empty(domain);
+        self
+    }
+
+    /// Only posts linking to this URL (prefix matches are up to the AppView).
+    pub fn url(mut self, url: Option<&str>) -> Self {
+        self.url = non_empty(url);
+        self
+    }
+
+    /// Only posts in this language, e.g. `en`.
+    pub fn lang(mut self, lang: Option<&str>) -> Self {
+        self.lang = non_empty(lang);
+        self
+    }
+
+    pub fn since(mut self, since: Option<DateTime<Utc>>) -> Self {
+        self.since = since;
+        self
+    }
+
+    pub fn until(mut self, until: Option<DateTime<Utc>>) -> Self {
+        self.until = until;
+        self
+    }
+
+    /// `latest` or `top`.
+    pub fn sort(mut self, sort: &str) -> Self {
+        self.sort = non_empty(Some(sort));
+        self
+    }
+
+    /// Whether anything narrows the search; an unfiltered query would return the whole firehose.
+    pub fn is_empty(&self) -> bool {
+        self.text.is_none()
+            && self.tags.is_empty()
+            && self.author.is_none()
+            && self.mentions.is_none()
+            && self.domain.is_none()
+            && self.url.is_none()
+    }
+
+    /// The `q` string: free text followed by `#tag`s, or `*` when only filters were given.
+    fn q(&self) -> String {
+        let parts: Vec<String> = self
+            .text
+            .iter()
+            .cloned()
+            .chain(self.tags.iter().map(|tag| format!("#{}", tag)))
+            .collect();
+        if parts.is_empty() {
+            "*".to_string()
+        } else {
+            parts.join(" ")
+        }
+    }
+
+    /// Query-string pairs for `searchPosts`, without `limit` or `cursor`.
+    pub fn to_query(&self) -> Vec<(&'static str, String)> {
+        let mut query = vec![("q", self.q())];
+        let optional = [
+            ("author", &self.author),
+            ("mentions", &self.mentions),
+            ("domain", &self.domain),
+            ("url", &self.url),
+            ("lang", &self.lang),
+            ("sort", &self.sort),
+        ];
+        for (key, value) in optional {
+            if let Some(value) = value {
+                query.push((key, value.clone()));
+            }
+        }
+        if let Some(since) = self.since {
+            query.push(("since", since.to_rfc3339_opts(SecondsFormat::Secs, true)));
+        }
+        if let Some(until) = self.until {
+            query.push(("until", until.to_rfc3339_opts(SecondsFormat::Secs, true)));
+        }
+        query
+    }
+}
+
+fn non_empty(value: Option<&str>) -> Option<String> {
+    value.map(str::trim).filter(|v| !v.is_empty()).map(str::to_string)
+}
+
+/// The search described by the widget parameters, minus the tags (which vary per OR branch).
+pub fn base_query(params: &Params) -> SearchQuery {
+    SearchQuery::new()
+        .text(params.text.as_deref())
+        .author(params.author.as_deref())
+        .mentions(params.mentions.as_deref())
+        .domain(params.domain.as_deref())
+        .url(params.url.as_deref())
+        .lang(params.lang.as_deref())
+        .since(params.maybe_since_time)
+        .until(params.maybe_until_time)
+        .sort(&params.sort)
+}
+
+/// Searches Bluesky posts for the widget's query. Each AND-only combination of the tag
+/// expression is searched in parallel, following `cursor` across pages and dropping posts with
+/// excluded tags as they arrive, then the results are merged and deduplicated by `uri`.
+/// Returns the requested page of posts and, for single searches, the cursor for the next page.
+pub async fn search_bluesky_posts(
+    client: &Client,
+    state: &BskyState,
+    token: Option<&str>,
+    params: &Params,
+) -> Result<(Vec<BskyPost>, Option<String>), Box<dyn std::error::Error>> {
+    let base = base_query(params);
+    let searches: Vec<SearchQuery> = if params.tags.is_empty() {
+        vec![base]
+    } else {
+        params.tags.searches().iter().map(|tags| base.clone().tags(tags)).collect()
+    };
+    // A cursor belongs to one search, so it only makes sense when there is exactly one
+    let start_cursor = params.cursor.clone().filter(|_| searches.len() == 1);
+
+    // Results are merged before the earlier pages are skipped, so this can't use `collect_page`
+    let skip = page_offset(params, start_cursor.as_deref());
+
+    let keep = |p: &BskyPost| !params.tags.excludes(p);
+    let results = try_join_all(
+        searches
+            .iter()
+            .map(|search| search_one(client, state, token, search, start_cursor.clone(), skip + params.limit, keep)),
+    )
+    .await?;
+
+    let single = results.len() == 1;
+    let mut seen = HashSet::new();
+    let mut cursor = None;
+    let mut posts = Vec::new();
+    for (page, next) in results {
+        if single {
+            cursor = next;
+        }
+        posts.extend(page.into_iter().filter(|p| seen.insert(p.uri.clone())));
+    }
+
+    posts.sort_by_key(|p| p.indexed_at.clone());
+    posts.reverse();
+    Ok((posts.into_iter().skip(skip).take(params.limit).collect(), cursor))
+}
+
+/// Runs one search, collecting up to `wanted` posts that `keep` accepts across pages.
+async fn search_one(
+    client: &Client,
+    state: &BskyState,
+    token: Option<&str>,
+    search: &SearchQuery,
+    start_cursor: Option<String>,
+    wanted: usize,
+    keep: impl Fn(&BskyPost) -> bool,
+) -> Result<(Vec<BskyPost>, Option<String>), Box<dyn std::error::Error>> {
+    let query = search.to_query();
+    let request = PageRequest {
+        method: "app.bsky.feed.searchPosts",
+        token,
+        query: &query,
+        page_size: SEARCH_PAGE_SIZE,
+    };
+    let extract = |result: BskySearchPostsResponse| (result.posts, result.cursor);
+    collect_pages(client, state, &request, start_cursor, wanted, extract, keep).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::AccountConfig;
+    use crate::auth::ServiceEndpoints;
+    use crate::params::parse_params;
+    use crate::post::fixtures::TestPost;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    fn post(n: u32, tags: &[&str]) -> Value {
+        TestPost::new(&n.to_string())
+            .indexed_at(&format!("2024-11-05T09:0{}:00Z", 9 - n))
+            .with_record("tags", tags)
+            .json()
+    }
+
+    /// Three pages of two, one and two posts, where every other post is tagged `hiring`.
+    async fn mock_search(query: web::Query<HashMap<String, String>>) -> HttpResponse {
+        let (posts, cursor) = match query.get("cursor").map(String::as_str) {
+            None => (vec![post(1, &["hiring"]), post(2, &[])], Some("2")),
+            Some("2") => (vec![post(3, &[]), post(4, &["hiring"])], Some("3")),
+            _ => (vec![post(5, &[])], None),
+        };
+        HttpResponse::Ok().json(json!({ "posts": posts, "cursor": cursor }))
+    }
+
+    fn mock_state() -> BskyState {
+        let server = HttpServer::new(|| App::new().route("/xrpc/app.bsky.feed.searchPosts", web::get().to(mock_search)))
+            .workers(1)
+            .disable_signals()
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let base = format!("http://{}", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+
+        let account = AccountConfig::default_account();
+        let mut endpoints = ServiceEndpoints::for_account(&account);
+        endpoints.public_appview = base;
+        BskyState {
+            token: Arc::new(Mutex::new(None)),
+            endpoints: Arc::new(Mutex::new(endpoints)),
+            oauth_pending: Arc::new(Mutex::new(HashMap::new())),
+            account,
+        }
+    }
+
+    fn uris(posts: &[BskyPost]) -> Vec<&str> {
+        posts.iter().map(|p| p.uri.rsplit('/').next().unwrap()).collect()
+    }
+
+    #[test]
+    fn q_combines_text_and_tags_or_falls_back_to_a_wildcard() {
+        let tags = vec!["rust".to_string(), "security".to_string()];
+        assert_eq!(
+            SearchQuery::new().text(Some(" memory safety ")).tags(&tags).q(),
+            "memory safety #rust #security"
+        );
+        assert_eq!(SearchQuery::new().tags(&tags).q(), "#rust #security");
+        assert_eq!(SearchQuery::new().text(Some("  ")).domain(Some("github.com")).q(), "*");
+    }
+
+    #[test]
+    fn to_query_strips_at_signs_and_formats_times() {
+        let since = DateTime::parse_from_rfc3339("2024-11-05T09:30:00.250+01:00")
+            .unwrap()
+            .with_timezone(&Utc);
+        let until = DateTime::parse_from_rfc3339("2024-11-06T00:00:00Z").unwrap().with_timezone(&Utc);
+        let query = SearchQuery::new()
+            .author(Some("@alice.bsky.social"))
+            .mentions(Some("@did:plc:bob"))
+            .lang(Some(""))
+            .since(Some(since))
+            .until(Some(until))
+            .sort("top")
+            .to_query();
+        let pairs: Vec<(&str, &str)> = query.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        assert_eq!(
+            pairs,
+            vec![
+                ("q", "*"),
+                ("author", "alice.bsky.social"),
+                ("mentions", "did:plc:bob"),
+                ("sort", "top"),
+                ("since", "2024-11-05T08:30:00Z"),
+                ("until", "2024-11-06T00:00:00Z"),
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn excluded_tags_do_not_use_up_the_page() {
+        let state = mock_state();
+        let client = Client::new();
+        let params = |page: &str| {
+            let query = [("tags", "rust,-hiring"), ("limit", "2"), ("page", page)];
+            parse_params(&query.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect())
+        };
+
+        let (first, _) = search_bluesky_posts(&client, &state, None, &params("1")).await.unwrap();
+        assert_eq!(uris(&first), vec!["2", "3"]);
+        let (second, _) = search_bluesky_posts(&client, &state, None, &params("2")).await.unwrap();
+        assert_eq!(uris(&second), vec!["5"]);
+    }
+}
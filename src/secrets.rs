@@ -0,0 +1,88 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock, RwLock};
+
+/// File contents read for `<NAME>_FILE` variables, kept until the next reload.
+fn cache() -> &'static RwLock<HashMap<String, String>> {
+    static CACHE: OnceLock<RwLock<HashMap<String, String>>> = OnceLock::new();
+    CACHE.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+/// Secrets whose unreadable `_FILE` has already been logged since the last reload.
+fn reported() -> &'static Mutex<HashSet<String>> {
+    static REPORTED: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+    REPORTED.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+/// Reads a secret following the Docker/Kubernetes `_FILE` convention: if `<name>_FILE` is set,
+/// the secret is that file's contents (trailing newline trimmed); otherwise it's `<name>` itself.
+/// Empty values count as unset. Errors only come from an unreadable `_FILE`.
+pub fn try_secret(name: &str) -> Result<Option<String>, String> {
+    let file_var = format!("{}_FILE", name);
+    let Some(path) = env::var(&file_var).ok().filter(|p| !p.trim().is_empty()) else {
+        return Ok(env::var(name).ok().filter(|v| !v.trim().is_empty()));
+    };
+
+    if let Some(value) = cache().read().unwrap_or_else(|e| e.into_inner()).get(name) {
+        return Ok(Some(value.clone()));
+    }
+    let contents = fs::read_to_string(&path).map_err(|e| format!("could not read {} ({}): {}", file_var, path, e))?;
+    let value = contents.trim_end_matches(['\r', '\n']).to_string();
+    if value.trim().is_empty() {
+        return Ok(None);
+    }
+    cache()
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(name.to_string(), value.clone());
+    Ok(Some(value))
+}
+
+/// Like `try_secret`, but treats an unreadable file as unset. Settings are read on every request,
+/// so each unreadable file is only logged once until the next reload.
+pub fn secret(name: &str) -> Option<String> {
+    try_secret(name).unwrap_or_else(|e| {
+        if reported().lock().unwrap_or_else(|e| e.into_inner()).insert(name.to_string()) {
+            println!("{}", e);
+        }
+        None
+    })
+}
+
+/// How many reloads have happened, so callers can tell whether secrets may have changed.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+pub fn generation() -> u64 {
+    GENERATION.load(Ordering::Relaxed)
+}
+
+/// Forgets cached file contents so the next lookup re-reads them.
+pub fn reload() {
+    cache().write().unwrap_or_else(|e| e.into_inner()).clear();
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+    reported().lock().unwrap_or_else(|e| e.into_inner()).clear();
+    println!("Secrets will be re-read from their files.");
+}
+
+/// Re-reads secret files whenever the process receives SIGHUP, so rotated credentials
+/// take effect without a restart.
+#[cfg(unix)]
+pub async fn reload_on_sighup() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            println!("Could not listen for SIGHUP, secrets won't be reloaded: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        reload();
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn reload_on_sighup() {}
@@ -0,0 +1,61 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::secrets::secret;
+use crate::store::write_atomic;
+
+const DEFAULT_STATE_FILE: &str = "bluesky_widget_state.json";
+
+/// Serialises read-modify-write cycles on the state file between concurrent renders.
+static STATE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Where member lists remember who they showed last time (`BLUESKY_STATE_FILE`).
+fn state_path() -> PathBuf {
+    secret("BLUESKY_STATE_FILE")
+        .unwrap_or_else(|| DEFAULT_STATE_FILE.to_string())
+        .into()
+}
+
+/// Compares `current` against the members recorded under `key` by the previous render, then
+/// records `current` for next time. Returns the members that weren't there before; the first
+/// render of a list has nothing to compare against, so nothing counts as new. This reads and
+/// writes the state file, so call it from a blocking thread rather than an async handler.
+pub fn new_since_last_render(key: &str, current: &[String]) -> HashSet<String> {
+    let _guard = STATE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let path = state_path();
+    let mut state = match load(&path) {
+        Ok(state) => state,
+        Err(e) => {
+            println!("Could not read widget state from {}: {}", path.display(), e);
+            HashMap::new()
+        }
+    };
+
+    let new = match state.get(key) {
+        Some(previous) => {
+            let previous: HashSet<&String> = previous.iter().collect();
+            current.iter().filter(|m| !previous.contains(m)).cloned().collect()
+        }
+        None => HashSet::new(),
+    };
+
+    state.insert(key.to_string(), current.to_vec());
+    let saved = serde_json::to_vec(&state)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        .and_then(|json| write_atomic(&path, &json));
+    if let Err(e) = saved {
+        println!("Could not save widget state to {}: {}", path.display(), e);
+    }
+    new
+}
+
+fn load(path: &Path) -> io::Result<HashMap<String, Vec<String>>> {
+    match fs::read(path) {
+        Ok(contents) => serde_json::from_slice(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e),
+    }
+}
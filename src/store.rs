@@ -0,0 +1,342 @@
+use argon2::Argon2;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Length of the random salt a passphrase is stretched with.
+const SALT_LEN: usize = 16;
+
+use crate::auth::BskySession;
+
+/// Why the session file couldn't be read or written.
+#[derive(Debug)]
+pub enum StoreError {
+    Io(io::Error),
+    /// The file exists but isn't a session we understand.
+    Format(String),
+    /// Encryption is configured but the key is unusable, or decryption failed.
+    Crypto(String),
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Io(err) => write!(f, "{}", err),
+            StoreError::Format(msg) => write!(f, "unreadable session file: {}", msg),
+            StoreError::Crypto(msg) => write!(f, "session encryption: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<io::Error> for StoreError {
+    fn from(err: io::Error) -> Self {
+        StoreError::Io(err)
+    }
+}
+
+/// What an encrypted session file looks like on disk. Version 1 is encrypted with a raw key,
+/// version 2 with a key stretched from a passphrase by Argon2id with the stored `salt`.
+#[derive(Serialize, Deserialize)]
+struct EncryptedFile {
+    version: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    salt: Option<String>,
+    nonce: String,
+    ciphertext: String,
+}
+
+#[derive(Clone)]
+enum Cipher {
+    Plain,
+    /// 32 random bytes, used as the key directly.
+    Key(Key),
+    /// Anything else, stretched into a key with Argon2id.
+    Passphrase(Arc<Passphrase>),
+    /// A key was configured but couldn't be loaded; refuse to touch the file rather than write plaintext.
+    Unavailable(String),
+}
+
+/// A passphrase and the key last stretched from it. Argon2 is deliberately slow, so the key is
+/// derived once per salt: when the store is built, and again only when loading a file that was
+/// written with a different salt. Saves reuse it with a fresh nonce.
+struct Passphrase {
+    secret: String,
+    derived: Mutex<([u8; SALT_LEN], Key)>,
+}
+
+impl Passphrase {
+    fn new(secret: String) -> Result<Self, StoreError> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let key = stretch(&secret, &salt)?;
+        Ok(Passphrase {
+            secret,
+            derived: Mutex::new((salt, key)),
+        })
+    }
+
+    /// The salt and key new files are written with.
+    fn current(&self) -> ([u8; SALT_LEN], Key) {
+        *self.derived.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The key for a file written with `salt`, stretching it only if the salt is new to us.
+    fn key_for(&self, salt: &[u8]) -> Result<Key, StoreError> {
+        let mut derived = self.derived.lock().unwrap_or_else(|e| e.into_inner());
+        if derived.0 == salt {
+            return Ok(derived.1);
+        }
+        let salt: [u8; SALT_LEN] = salt.try_into().map_err(|_| StoreError::Format("bad salt length".to_string()))?;
+        let key = stretch(&self.secret, &salt)?;
+        *derived = (salt, key);
+        Ok(key)
+    }
+}
+
+/// Persists one account's session to a file, optionally encrypted with ChaCha20-Poly1305.
+#[derive(Clone)]
+pub struct SessionStore {
+    path: PathBuf,
+    cipher: Cipher,
+}
+
+impl std::fmt::Debug for SessionStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let cipher = match &self.cipher {
+            Cipher::Plain => "plain",
+            Cipher::Key(_) | Cipher::Passphrase(_) => "encrypted",
+            Cipher::Unavailable(_) => "unavailable",
+        };
+        f.debug_struct("SessionStore")
+            .field("path", &self.path)
+            .field("cipher", &cipher)
+            .finish()
+    }
+}
+
+impl SessionStore {
+    /// `key` is the raw key setting: 32 bytes of base64, or any other string used as a passphrase.
+    /// `key_error` is set when a key was configured but couldn't be read.
+    pub fn new(path: PathBuf, key: Option<String>, key_error: Option<String>) -> Self {
+        let cipher = match (key, key_error) {
+            (_, Some(err)) => Cipher::Unavailable(err),
+            (Some(key), None) => cipher_for(key.trim()),
+            (None, None) => Cipher::Plain,
+        };
+        SessionStore { path, cipher }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads the stored session. A missing file is `Ok(None)`; plaintext files are still
+    /// accepted when encryption is on, and get encrypted on the next save.
+    pub fn load(&self) -> Result<Option<BskySession>, StoreError> {
+        let contents = match fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let plaintext = match serde_json::from_slice::<EncryptedFile>(&contents) {
+            Ok(file) => match &self.cipher {
+                Cipher::Plain => return Err(StoreError::Crypto("session file is encrypted but no key is configured".to_string())),
+                Cipher::Unavailable(err) => return Err(StoreError::Crypto(err.clone())),
+                cipher => decrypt(cipher, &file)?,
+            },
+            Err(_) => contents,
+        };
+        serde_json::from_slice(&plaintext)
+            .map(Some)
+            .map_err(|e| StoreError::Format(e.to_string()))
+    }
+
+    /// Writes the session atomically, encrypted if a key is configured.
+    pub fn save(&self, session: &BskySession) -> Result<(), StoreError> {
+        let json = serde_json::to_vec(session).map_err(|e| StoreError::Format(e.to_string()))?;
+        let contents = match &self.cipher {
+            Cipher::Plain => json,
+            Cipher::Unavailable(err) => return Err(StoreError::Crypto(err.clone())),
+            cipher => serde_json::to_vec(&encrypt(cipher, &json)?).map_err(|e| StoreError::Format(e.to_string()))?,
+        };
+
+        Ok(write_atomic(&self.path, &contents)?)
+    }
+}
+
+/// Writes `contents` to a temporary file next to `path`, readable by its owner only, and renames
+/// it into place, so a crash never leaves a half-written file behind.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    // Unique per write, so concurrent writes in one process never share a temporary file
+    static WRITES: AtomicU64 = AtomicU64::new(0);
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(format!(".tmp-{}-{}", std::process::id(), WRITES.fetch_add(1, Ordering::Relaxed)));
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let result = write_private(&tmp_path, contents).and_then(|_| fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Creates the file readable by its owner only, since it holds live credentials.
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+fn cipher_for(raw: &str) -> Cipher {
+    match STANDARD.decode(raw) {
+        Ok(bytes) if bytes.len() == 32 => Cipher::Key(*Key::from_slice(&bytes)),
+        _ => match Passphrase::new(raw.to_string()) {
+            Ok(passphrase) => Cipher::Passphrase(Arc::new(passphrase)),
+            Err(err) => Cipher::Unavailable(err.to_string()),
+        },
+    }
+}
+
+/// Stretches a passphrase into a key with Argon2id, so a stolen file can't be cheaply brute-forced.
+fn stretch(passphrase: &str, salt: &[u8]) -> Result<Key, StoreError> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| StoreError::Crypto(format!("key derivation failed: {}", e)))?;
+    Ok(key)
+}
+
+fn encrypt(cipher: &Cipher, plaintext: &[u8]) -> Result<EncryptedFile, StoreError> {
+    let (version, salt, key) = match cipher {
+        Cipher::Key(key) => (1, None, *key),
+        Cipher::Passphrase(passphrase) => {
+            let (salt, key) = passphrase.current();
+            (2, Some(STANDARD.encode(salt)), key)
+        }
+        Cipher::Plain | Cipher::Unavailable(_) => return Err(StoreError::Crypto("no key configured".to_string())),
+    };
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = ChaCha20Poly1305::new(&key)
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| StoreError::Crypto("encryption failed".to_string()))?;
+    Ok(EncryptedFile {
+        version,
+        salt,
+        nonce: STANDARD.encode(nonce),
+        ciphertext: STANDARD.encode(ciphertext),
+    })
+}
+
+fn decrypt(cipher: &Cipher, file: &EncryptedFile) -> Result<Vec<u8>, StoreError> {
+    let key = match (file.version, cipher) {
+        (1, Cipher::Key(key)) => *key,
+        (1, _) => {
+            return Err(StoreError::Crypto(
+                "session file was encrypted with a raw key, but the configured key is a passphrase".to_string(),
+            ))
+        }
+        (2, Cipher::Passphrase(passphrase)) => {
+            let salt = file.salt.as_deref().ok_or_else(|| StoreError::Format("missing salt".to_string()))?;
+            passphrase.key_for(&STANDARD.decode(salt).map_err(|e| StoreError::Format(e.to_string()))?)?
+        }
+        (2, _) => {
+            return Err(StoreError::Crypto(
+                "session file was encrypted with a passphrase, but the configured key is 32 raw bytes".to_string(),
+            ))
+        }
+        (version, _) => return Err(StoreError::Format(format!("unsupported version {}", version))),
+    };
+    let nonce = STANDARD.decode(&file.nonce).map_err(|e| StoreError::Format(e.to_string()))?;
+    let ciphertext = STANDARD.decode(&file.ciphertext).map_err(|e| StoreError::Format(e.to_string()))?;
+    if nonce.len() != 12 {
+        return Err(StoreError::Format("bad nonce length".to_string()));
+    }
+    ChaCha20Poly1305::new(&key)
+        .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+        .map_err(|_| StoreError::Crypto("could not decrypt session file; wrong key?".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> BskySession {
+        BskySession {
+            access_jwt: "access".to_string(),
+            refresh_jwt: "refresh".to_string(),
+            did: "did:plc:test".to_string(),
+            pds_endpoint: None,
+            oauth: None,
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bluesky-store-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    fn read_file(path: &Path) -> EncryptedFile {
+        serde_json::from_slice(&fs::read(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn passphrase_keys_are_derived_once_per_salt() {
+        let path = temp_path("salted.json");
+        let store = SessionStore::new(path.clone(), Some("correct horse".to_string()), None);
+        store.save(&session()).unwrap();
+        let first = read_file(&path);
+        assert_eq!(first.version, 2);
+        assert_eq!(store.load().unwrap().unwrap().did, "did:plc:test");
+
+        // Saves reuse the derived key and its salt, but never a nonce
+        store.save(&session()).unwrap();
+        let second = read_file(&path);
+        assert_eq!(first.salt, second.salt);
+        assert_ne!(first.nonce, second.nonce);
+
+        // Another store has its own salt, and reads this file by stretching the passphrase with the stored one
+        let restarted = SessionStore::new(path.clone(), Some("correct horse".to_string()), None);
+        assert_eq!(restarted.load().unwrap().unwrap().refresh_jwt, "refresh");
+        restarted.save(&session()).unwrap();
+        assert_eq!(read_file(&path).salt, first.salt);
+
+        let wrong = SessionStore::new(path.clone(), Some("wrong horse".to_string()), None);
+        assert!(matches!(wrong.load(), Err(StoreError::Crypto(_))));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn concurrent_saves_leave_a_readable_file() {
+        let path = temp_path("concurrent.json");
+        let store = SessionStore::new(path.clone(), None, None);
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| store.save(&session()).unwrap());
+            }
+        });
+        assert_eq!(store.load().unwrap().unwrap().did, "did:plc:test");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+use crate::post::BskyPost;
+
+/// Most searches one tag expression may expand into.
+pub const MAX_TAG_SEARCHES: usize = 8;
+
+/// A parsed `tags` parameter such as `rust|rustlang,security,-hiring`.
+/// Comma-separated terms are ANDed, `|` separates alternatives within a term,
+/// and a leading `-` excludes posts carrying that hashtag.
+#[derive(Debug, Default)]
+pub struct TagExpr {
+    /// Each group must match; any tag within a group will do.
+    pub groups: Vec<Vec<String>>,
+    /// Lowercased tags that drop a post when present in its hashtag facets.
+    pub excluded: Vec<String>,
+}
+
+impl TagExpr {
+    /// Parses `spec`, refusing expressions whose `|` alternatives expand into more than
+    /// `MAX_TAG_SEARCHES` searches.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut expr = TagExpr::default();
+        for term in spec.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            if let Some(excluded) = term.strip_prefix('-') {
+                let tag = normalize(excluded);
+                if !tag.is_empty() {
+                    expr.excluded.push(tag.to_lowercase());
+                }
+                continue;
+            }
+            let group: Vec<String> = term
+                .split('|')
+                .map(normalize)
+                .filter(|t| !t.is_empty())
+                .map(str::to_string)
+                .collect();
+            if !group.is_empty() {
+                expr.groups.push(group);
+            }
+        }
+
+        let searches = expr.groups.iter().fold(1usize, |count, group| count.saturating_mul(group.len()));
+        if searches > MAX_TAG_SEARCHES {
+            return Err(format!(
+                "\"{}\" expands into {} searches, at most {} are allowed",
+                spec, searches, MAX_TAG_SEARCHES
+            ));
+        }
+        Ok(expr)
+    }
+
+    /// True when there is nothing to search for (exclusions alone can't drive a search).
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// Expands the OR groups into the AND-only tag lists to search for, e.g.
+    /// `a|b,c` becomes `[a, c]` and `[b, c]`.
+    pub fn searches(&self) -> Vec<Vec<String>> {
+        let mut searches: Vec<Vec<String>> = vec![Vec::new()];
+        for group in &self.groups {
+            searches = searches
+                .iter()
+                .flat_map(|prefix| {
+                    group.iter().map(move |tag| {
+                        let mut search = prefix.clone();
+                        search.push(tag.clone());
+                        search
+                    })
+                })
+                .collect();
+        }
+        searches
+    }
+
+    /// Whether `post` carries any excluded hashtag.
+    pub fn excludes(&self, post: &BskyPost) -> bool {
+        if self.excluded.is_empty() {
+            return false;
+        }
+        post.record.hashtags().iter().any(|tag| self.excluded.contains(&tag.to_lowercase()))
+    }
+}
+
+/// Tags may be written with or without the leading `#`.
+fn normalize(tag: &str) -> &str {
+    tag.trim().trim_start_matches('#')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post::fixtures::TestPost;
+    use serde_json::json;
+
+    fn post_with_tags(facet_tags: &[&str], record_tags: &[&str]) -> BskyPost {
+        let features: Vec<_> = facet_tags
+            .iter()
+            .map(|tag| json!({ "$type": "app.bsky.richtext.facet#tag", "tag": tag }))
+            .collect();
+        TestPost::new("1")
+            .with_record("facets", json!([{ "features": features }]))
+            .with_record("tags", record_tags)
+            .build()
+    }
+
+    #[test]
+    fn parses_groups_and_exclusions() {
+        let expr = TagExpr::parse(" #rust|rustlang , security,-Hiring,-#Jobs,,").unwrap();
+        assert_eq!(expr.groups, vec![vec!["rust", "rustlang"], vec!["security"]]);
+        assert_eq!(expr.excluded, vec!["hiring", "jobs"]);
+    }
+
+    #[test]
+    fn empty_and_exclusion_only_expressions_have_nothing_to_search() {
+        assert!(TagExpr::parse("").unwrap().is_empty());
+        assert!(TagExpr::parse(" , | ,#").unwrap().is_empty());
+        let only_excluded = TagExpr::parse("-spam").unwrap();
+        assert!(only_excluded.is_empty());
+        assert_eq!(only_excluded.excluded, vec!["spam"]);
+    }
+
+    #[test]
+    fn expands_alternatives_into_and_only_searches() {
+        let expr = TagExpr::parse("a|b,c,d|e").unwrap();
+        assert_eq!(
+            expr.searches(),
+            vec![vec!["a", "c", "d"], vec!["a", "c", "e"], vec!["b", "c", "d"], vec!["b", "c", "e"]]
+        );
+        assert_eq!(TagExpr::parse("rust").unwrap().searches(), vec![vec!["rust"]]);
+    }
+
+    #[test]
+    fn refuses_expressions_with_too_many_searches() {
+        assert_eq!(TagExpr::parse("a|b,c|d,e|f").unwrap().searches().len(), MAX_TAG_SEARCHES);
+        let err = TagExpr::parse("a|b,c|d,e|f|g").unwrap_err();
+        assert!(err.contains("12 searches"), "{}", err);
+    }
+
+    #[test]
+    fn excludes_posts_by_facet_or_record_tag_ignoring_case() {
+        let expr = TagExpr::parse("rust,-hiring").unwrap();
+        assert!(expr.excludes(&post_with_tags(&["Hiring"], &[])));
+        assert!(expr.excludes(&post_with_tags(&[], &["HIRING"])));
+        assert!(!expr.excludes(&post_with_tags(&["rust"], &["jobs"])));
+        assert!(!TagExpr::parse("rust").unwrap().excludes(&post_with_tags(&["hiring"], &[])));
+    }
+}
@@ -0,0 +1,227 @@
+use html_escape::encode_double_quoted_attribute;
+use reqwest::Client;
+
+use crate::aturi::{post_web_link, RecordRef};
+use crate::auth::{xrpc_get, BskyState};
+use crate::params::Params;
+use crate::post::{BskyGetPostThreadResponse, BskyThreadNode};
+use crate::render::build_post_html;
+
+/// Deepest reply level `getPostThread` will fetch for us.
+pub const MAX_THREAD_DEPTH: usize = 10;
+/// `sort` values for threads: most liked, newest or oldest replies first.
+pub const THREAD_SORTS: [&str; 3] = ["top", "latest", "oldest"];
+
+/// Fetches a post and its replies down to `depth` levels via `app.bsky.feed.getPostThread`,
+/// with every level of replies sorted as `sort` asks.
+pub async fn fetch_thread(
+    client: &Client,
+    state: &BskyState,
+    token: Option<&str>,
+    post: &RecordRef,
+    params: &Params,
+) -> Result<BskyThreadNode, Box<dyn std::error::Error>> {
+    let uri = post.resolve(client, state, token).await?;
+    let query = [
+        ("uri", uri.clone()),
+        ("depth", params.depth.to_string()),
+        ("parentHeight", "0".to_string()),
+    ];
+    let response = xrpc_get(client, state, "app.bsky.feed.getPostThread", token, &query).await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        // A missing root comes back as an error rather than a notFoundPost node
+        if body.contains("NotFound") {
+            return Ok(BskyThreadNode::NotFound { uri });
+        }
+        return Err(format!("{}: {}", status, body).into());
+    }
+    let mut thread = serde_json::from_str::<BskyGetPostThreadResponse>(&response.text().await?)?.thread;
+    sort_replies(&mut thread, &params.sort);
+    Ok(thread)
+}
+
+/// Sorts replies at every level. Hidden (blocked or deleted) replies go last.
+fn sort_replies(node: &mut BskyThreadNode, sort: &str) {
+    let BskyThreadNode::Post(view) = node else {
+        return;
+    };
+    view.replies.sort_by(|a, b| match (a, b) {
+        (BskyThreadNode::Post(a), BskyThreadNode::Post(b)) => match sort {
+            "top" => b.post.like_count.cmp(&a.post.like_count),
+            "oldest" => a.post.indexed_at.cmp(&b.post.indexed_at),
+            _ => b.post.indexed_at.cmp(&a.post.indexed_at),
+        },
+        (BskyThreadNode::Post(_), _) => std::cmp::Ordering::Less,
+        (_, BskyThreadNode::Post(_)) => std::cmp::Ordering::Greater,
+        _ => std::cmp::Ordering::Equal,
+    });
+    for reply in &mut view.replies {
+        sort_replies(reply, sort);
+    }
+}
+
+/// Renders the thread root with its replies nested beneath it. Each level shows at most `limit`
+/// replies; the top level collapses after `collapse-after`.
+pub fn build_thread_html(thread: &BskyThreadNode, body: &mut String, params: &Params) {
+    let BskyThreadNode::Post(root) = thread else {
+        body.push_str(&format!("<p>{}</p>", hidden_node_text(thread)));
+        return;
+    };
+    body.push_str(r#"<ul class="list"><li class="post-container">"#);
+    build_post_html(&root.post, body, params);
+    build_replies_html(&root.replies, root.post.reply_count, &root.post.uri, body, params, true);
+    body.push_str("</li></ul>");
+}
+
+fn build_replies_html(
+    replies: &[BskyThreadNode],
+    reply_count: Option<u32>,
+    parent_uri: &str,
+    body: &mut String,
+    params: &Params,
+    top_level: bool,
+) {
+    let shown: Vec<&BskyThreadNode> = replies
+        .iter()
+        .filter(|r| !matches!(r, BskyThreadNode::Unknown))
+        .take(params.limit)
+        .collect();
+    // Replies past the depth we fetched (or past `limit`) are only counted, not listed. Blocked and
+    // deleted placeholders aren't part of `reply_count`, so they don't reduce it either.
+    let shown_posts = shown.iter().filter(|r| matches!(r, BskyThreadNode::Post(_))).count();
+    let unshown = (reply_count.unwrap_or(0) as usize).saturating_sub(shown_posts);
+
+    if !shown.is_empty() {
+        if top_level {
+            body.push_str(&format!(
+                r#"<ul class="list collapsible-container thread-replies" data-collapse-after="{}">"#,
+                params.collapse_after
+            ));
+        } else {
+            body.push_str(r#"<ul class="list thread-replies">"#);
+        }
+        for reply in shown {
+            body.push_str(r#"<li class="post-container">"#);
+            match reply {
+                BskyThreadNode::Post(view) => {
+                    build_post_html(&view.post, body, params);
+                    build_replies_html(&view.replies, view.post.reply_count, &view.post.uri, body, params, false);
+                }
+                other => body.push_str(&format!(r#"<p class="post-author">{}</p>"#, hidden_node_text(other))),
+            }
+            body.push_str("</li>");
+        }
+        body.push_str("</ul>");
+    }
+
+    if unshown > 0 {
+        body.push_str(&format!(
+            r#"<p class="post-stats thread-more"><a href="{}" target="_blank">{} more {}</a></p>"#,
+            encode_double_quoted_attribute(&post_web_link(parent_uri).unwrap_or_default()),
+            unshown,
+            if unshown == 1 { "reply" } else { "replies" }
+        ));
+    }
+}
+
+fn hidden_node_text(node: &BskyThreadNode) -> &'static str {
+    match node {
+        BskyThreadNode::Blocked { .. } => "Blocked post",
+        BskyThreadNode::NotFound { .. } => "Deleted or missing post",
+        _ => "Unavailable post",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post::fixtures::TestPost;
+    use serde_json::{json, Value};
+
+    fn post(rkey: &str, likes: u32, indexed_at: &str, replies: Vec<Value>) -> Value {
+        json!({
+            "$type": "app.bsky.feed.defs#threadViewPost",
+            "post": TestPost::new(rkey).indexed_at(indexed_at).with("likeCount", likes).with_record("text", rkey).json(),
+            "replies": replies,
+        })
+    }
+
+    fn blocked(rkey: &str) -> Value {
+        json!({ "$type": "app.bsky.feed.defs#blockedPost", "uri": format!("at://did:plc:b/app.bsky.feed.post/{}", rkey) })
+    }
+
+    fn thread() -> BskyThreadNode {
+        let replies = vec![
+            blocked("hidden"),
+            post("old", 5, "2024-11-05T09:00:00Z", vec![]),
+            post(
+                "new",
+                1,
+                "2024-11-05T11:00:00Z",
+                vec![
+                    blocked("nested"),
+                    post("b", 0, "2024-11-05T12:00:00Z", vec![]),
+                    post("a", 9, "2024-11-05T13:00:00Z", vec![]),
+                ],
+            ),
+            post("mid", 7, "2024-11-05T10:00:00Z", vec![]),
+        ];
+        serde_json::from_value(post("root", 0, "2024-11-05T08:00:00Z", replies)).unwrap()
+    }
+
+    fn order(node: &BskyThreadNode) -> Vec<String> {
+        let BskyThreadNode::Post(view) = node else {
+            return vec![];
+        };
+        view.replies
+            .iter()
+            .map(|reply| match reply {
+                BskyThreadNode::Post(view) => view.post.uri.rsplit('/').next().unwrap().to_string(),
+                BskyThreadNode::Blocked { .. } => "blocked".to_string(),
+                _ => "other".to_string(),
+            })
+            .collect()
+    }
+
+    fn replies_of<'a>(node: &'a BskyThreadNode, rkey: &str) -> &'a BskyThreadNode {
+        let BskyThreadNode::Post(view) = node else {
+            panic!("not a post");
+        };
+        view.replies
+            .iter()
+            .find(|r| matches!(r, BskyThreadNode::Post(v) if v.post.uri.ends_with(rkey)))
+            .unwrap()
+    }
+
+    #[test]
+    fn sorts_replies_at_every_level_with_hidden_ones_last() {
+        let mut top = thread();
+        sort_replies(&mut top, "top");
+        assert_eq!(order(&top), ["mid", "old", "new", "blocked"]);
+        assert_eq!(order(replies_of(&top, "new")), ["a", "b", "blocked"]);
+
+        let mut latest = thread();
+        sort_replies(&mut latest, "latest");
+        assert_eq!(order(&latest), ["new", "mid", "old", "blocked"]);
+        assert_eq!(order(replies_of(&latest, "new")), ["a", "b", "blocked"]);
+
+        let mut oldest = thread();
+        sort_replies(&mut oldest, "oldest");
+        assert_eq!(order(&oldest), ["old", "mid", "new", "blocked"]);
+        assert_eq!(order(replies_of(&oldest, "new")), ["b", "a", "blocked"]);
+    }
+
+    #[test]
+    fn hidden_replies_do_not_count_towards_the_shown_replies() {
+        let BskyThreadNode::Post(root) = thread() else {
+            panic!("not a post");
+        };
+        let params = crate::params::parse_params(&Default::default());
+        let mut body = String::new();
+        // Four replies were fetched, but the blocked one isn't one of the five in reply_count
+        build_replies_html(&root.replies, Some(5), &root.post.uri, &mut body, &params, true);
+        assert!(body.contains(">2 more replies</a>"), "{}", body);
+    }
+}
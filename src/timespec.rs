@@ -0,0 +1,195 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+
+/// Why a `since`/`until` value couldn't be understood.
+#[derive(Debug, PartialEq)]
+pub struct TimeSpecError {
+    spec: String,
+    reason: String,
+}
+
+impl TimeSpecError {
+    fn new(spec: &str, reason: impl Into<String>) -> Self {
+        TimeSpecError {
+            spec: spec.to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for TimeSpecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "\"{}\": {}", self.spec, self.reason)
+    }
+}
+
+impl std::error::Error for TimeSpecError {}
+
+/// Parses a `since`/`until` value into an instant, relative to `now`. Accepts:
+/// - durations ago, optionally compound: `-4h`, `-1d12h`, `-2w` (units `w`, `d`, `h`, `m`, `s`)
+/// - named anchors, all in UTC: `now`, `today`, `yesterday`, `this-week`, `last-week`, `this-month`, `this-year`
+/// - RFC 3339 timestamps (`2024-11-05T09:00:00Z`) and plain dates or times taken as UTC
+///   (`2024-11-05`, `2024-11-05T09:00`)
+pub fn parse_time_spec(spec: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>, TimeSpecError> {
+    let spec = spec.trim();
+    if spec.is_empty() {
+        return Err(TimeSpecError::new(spec, "empty time"));
+    }
+    if let Some(duration) = spec.strip_prefix('-') {
+        let ago = parse_duration(duration).map_err(|reason| TimeSpecError::new(spec, reason))?;
+        return now
+            .checked_sub_signed(ago)
+            .ok_or_else(|| TimeSpecError::new(spec, "too far in the past"));
+    }
+    if let Some(anchor) = parse_anchor(spec, now) {
+        return Ok(anchor);
+    }
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(spec) {
+        return Ok(datetime.with_timezone(&Utc));
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"] {
+        if let Ok(datetime) = NaiveDateTime::parse_from_str(spec, format) {
+            return Ok(Utc.from_utc_datetime(&datetime));
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(spec, "%Y-%m-%d") {
+        return Ok(start_of_day(date));
+    }
+    Err(TimeSpecError::new(
+        spec,
+        "expected a duration like -4h or -1d12h, a date like 2024-11-05, an RFC 3339 time, or one of now, today, yesterday, this-week, last-week, this-month, this-year",
+    ))
+}
+
+/// Parses `1d12h`-style durations: one or more `<int><unit>` pairs.
+fn parse_duration(spec: &str) -> Result<Duration, String> {
+    if spec.is_empty() {
+        return Err("missing duration after -".to_string());
+    }
+    let mut total = Duration::zero();
+    let mut digits = String::new();
+    for c in spec.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        if digits.is_empty() {
+            return Err(format!("expected a number before '{}'", c));
+        }
+        let amount: i64 = digits.parse().map_err(|_| format!("{} is too large", digits))?;
+        let part = match c {
+            'w' => Duration::try_weeks(amount),
+            'd' => Duration::try_days(amount),
+            'h' => Duration::try_hours(amount),
+            'm' => Duration::try_minutes(amount),
+            's' => Duration::try_seconds(amount),
+            _ => return Err(format!("unknown unit '{}', use w, d, h, m or s", c)),
+        };
+        total = part
+            .and_then(|part| total.checked_add(&part))
+            .ok_or_else(|| "duration is too large".to_string())?;
+        digits.clear();
+    }
+    if !digits.is_empty() {
+        return Err(format!("{} is missing a unit (w, d, h, m or s)", digits));
+    }
+    Ok(total)
+}
+
+/// Calendar anchors. Weeks start on Monday.
+fn parse_anchor(spec: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let today = now.date_naive();
+    let this_week = today - Duration::days(today.weekday().num_days_from_monday() as i64);
+    let anchor = match spec.to_ascii_lowercase().as_str() {
+        "now" => return Some(now),
+        "today" => today,
+        "yesterday" => today.pred_opt()?,
+        "this-week" => this_week,
+        "last-week" => this_week - Duration::days(7),
+        "this-month" => today.with_day(1)?,
+        "this-year" => NaiveDate::from_ymd_opt(today.year(), 1, 1)?,
+        _ => return None,
+    };
+    Some(start_of_day(anchor))
+}
+
+fn start_of_day(date: NaiveDate) -> DateTime<Utc> {
+    Utc.from_utc_datetime(&date.and_time(NaiveTime::MIN))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A Thursday afternoon, so week anchors have to reach back to Monday.
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 11, 7, 15, 30, 0).unwrap()
+    }
+
+    fn at(spec: &str) -> DateTime<Utc> {
+        parse_time_spec(spec, now()).unwrap()
+    }
+
+    fn utc(y: i32, mo: u32, d: u32, h: u32, mi: u32, s: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, s).unwrap()
+    }
+
+    #[test]
+    fn parses_simple_and_compound_durations() {
+        assert_eq!(at("-4h"), utc(2024, 11, 7, 11, 30, 0));
+        assert_eq!(at("-2w"), utc(2024, 10, 24, 15, 30, 0));
+        assert_eq!(at("-1d12h"), utc(2024, 11, 6, 3, 30, 0));
+        assert_eq!(at("-1h30m15s"), utc(2024, 11, 7, 13, 59, 45));
+        assert_eq!(at(" -0s "), now());
+    }
+
+    #[test]
+    fn parses_calendar_anchors_at_utc_midnight() {
+        assert_eq!(at("now"), now());
+        assert_eq!(at("today"), utc(2024, 11, 7, 0, 0, 0));
+        assert_eq!(at("Yesterday"), utc(2024, 11, 6, 0, 0, 0));
+        assert_eq!(at("this-week"), utc(2024, 11, 4, 0, 0, 0));
+        assert_eq!(at("last-week"), utc(2024, 10, 28, 0, 0, 0));
+        assert_eq!(at("this-month"), utc(2024, 11, 1, 0, 0, 0));
+        assert_eq!(at("this-year"), utc(2024, 1, 1, 0, 0, 0));
+    }
+
+    #[test]
+    fn this_week_on_a_monday_is_that_morning() {
+        let monday = utc(2024, 11, 4, 8, 0, 0);
+        assert_eq!(parse_time_spec("this-week", monday).unwrap(), utc(2024, 11, 4, 0, 0, 0));
+        assert_eq!(parse_time_spec("last-week", monday).unwrap(), utc(2024, 10, 28, 0, 0, 0));
+    }
+
+    #[test]
+    fn parses_rfc3339_and_naive_times_as_utc() {
+        assert_eq!(at("2024-11-05T09:00:00Z"), utc(2024, 11, 5, 9, 0, 0));
+        assert_eq!(at("2024-11-05T09:00:00+02:00"), utc(2024, 11, 5, 7, 0, 0));
+        assert_eq!(at("2024-11-05T09:00:30"), utc(2024, 11, 5, 9, 0, 30));
+        assert_eq!(at("2024-11-05T09:00"), utc(2024, 11, 5, 9, 0, 0));
+        assert_eq!(at("2024-11-05 09:00"), utc(2024, 11, 5, 9, 0, 0));
+        assert_eq!(at("2024-11-05"), utc(2024, 11, 5, 0, 0, 0));
+    }
+
+    #[test]
+    fn rejects_malformed_specs() {
+        for spec in [
+            "",
+            "   ",
+            "-",
+            "-h",
+            "-4",
+            "-4x",
+            "-1d2",
+            "4h",
+            "next-week",
+            "2024-13-01",
+            "-99999999999999999999d",
+        ] {
+            assert!(parse_time_spec(spec, now()).is_err(), "{:?} should be rejected", spec);
+        }
+        assert_eq!(
+            parse_time_spec("-4x", now()).unwrap_err().to_string(),
+            "\"-4x\": unknown unit 'x', use w, d, h, m or s"
+        );
+    }
+}